@@ -1,11 +1,308 @@
-#[derive(Debug)]
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::kv::LineFormat;
+
+/// What to do when a changed value switches between structured (JSON) and scalar
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Warn,
+    Error,
+    #[default]
+    Overwrite,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(ConflictPolicy::Warn),
+            "error" => Ok(ConflictPolicy::Error),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            _ => Err(format!("unknown conflict policy {}", s)),
+        }
+    }
+}
+
+/// What to do when a CAS write finds the key modified since it was read
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CasFailPolicy {
+    Skip,
+    #[default]
+    Error,
+}
+
+impl FromStr for CasFailPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CasFailPolicy::Skip),
+            "error" => Ok(CasFailPolicy::Error),
+            _ => Err(format!("unknown CAS failure policy {}", s)),
+        }
+    }
+}
+
+/// Format of the publish report printed to stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only log the summary
+    #[default]
+    Text,
+    /// Print statistics as a JSON object
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format {}", s)),
+        }
+    }
+}
+
+/// Format of log lines written to stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with service, env and key context
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Age of keys given as a number with a `s`, `m`, `h` or `d` suffix, e.g. `7d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Age(pub Duration);
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((pos, 's')) => (&s[..pos], 1),
+            Some((pos, 'm')) => (&s[..pos], 60),
+            Some((pos, 'h')) => (&s[..pos], 60 * 60),
+            Some((pos, 'd')) => (&s[..pos], 24 * 60 * 60),
+            _ => return Err(format!("age {} must end with s, m, h or d", s)),
+        };
+        let number: u64 = number.parse().map_err(|_| format!("invalid age {}", s))?;
+        Ok(Age(Duration::from_secs(number * multiplier)))
+    }
+}
+
+/// Consul token of a single service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceToken {
+    pub service: String,
+    pub token: String,
+}
+
+impl FromStr for ServiceToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((service, token)) if !service.trim().is_empty() => Ok(ServiceToken {
+                service: service.trim().to_string(),
+                token: token.trim().to_string(),
+            }),
+            _ => Err(format!("expected service=token, got {}", s)),
+        }
+    }
+}
+
+/// Extra variable of the key template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateVar {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for TemplateVar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok(TemplateVar {
+                name: name.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(format!("expected name=value, got {}", s)),
+        }
+    }
+}
+
+/// Rename of a config key as `old=new`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRename {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for KeyRename {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok(KeyRename {
+                from: from.trim().to_string(),
+                to: to.trim().to_string(),
+            }),
+            _ => Err(format!("expected old=new, got {}", s)),
+        }
+    }
+}
+
+/// Read `service=token` lines from a file, skipping empty lines and `#` comments
+pub fn read_service_tokens(path: &Path) -> Result<Vec<ServiceToken>, Error> {
+    let content = std::fs::read_to_string(path).map_err(Error::ConfigFile)?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse::<ServiceToken>().map_err(Error::ConfigFormat))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Config {
     pub consul_addr: String,
     pub consul_token: String,
+    /// File with the Consul token, overrides `consul_token`
+    pub consul_token_file: Option<String>,
+    pub consul_ca_cert: Option<String>,
+    pub consul_client_cert: Option<String>,
+    pub consul_client_key: Option<String>,
+    /// Consul datacenter, or a comma-separated list of datacenters to publish to one by one
+    pub datacenter: Option<String>,
+    pub namespace: Option<String>,
+    pub partition: Option<String>,
+    pub service_tokens: HashMap<String, String>,
     pub service: Option<String>,
     pub env: Option<String>,
     pub filter_env: Option<String>,
+    pub filter_service: Option<String>,
+    /// Match service and env filters case-insensitively
+    pub ignore_case: bool,
+    /// Lowercase service and env names deduced from config file paths
+    pub lowercase_names: bool,
+    pub filename_pattern: Option<String>,
+    pub service_from_dir: bool,
+    /// Path components supplying service and env, e.g. `env/service`
+    pub layout: Option<String>,
     pub config_path: String,
+    pub matrix: Option<String>,
+    /// Path to a merged config file with keys of several services and envs
+    pub merged: Option<String>,
+    /// Regex with `service`, `env` and `key` named groups splitting keys of a merged file
+    pub merged_pattern: Option<String>,
+    pub allow_local_overrides: bool,
+    pub fail_on_empty: bool,
+    pub no_compare: Vec<String>,
+    /// Globs of keys to publish, all keys if empty
+    pub include_keys: Vec<String>,
+    /// Globs of keys to leave alone, neither written nor deleted
+    pub exclude_keys: Vec<String>,
+    /// Path prefix removed from every key of config files
+    pub key_prefix_strip: Option<String>,
+    /// Path prefix added to every key of config files after stripping
+    pub key_prefix_add: Option<String>,
+    /// New names of config keys by old names, old keys are deleted from Consul
+    pub rename_keys: HashMap<String, String>,
+    pub validate_json_values: Option<String>,
+    /// Regex every published key must match
+    pub key_regex: Option<String>,
+    /// List keys of a prefix first and read values only of keys in config files
+    pub read_keys_first: bool,
+    pub canonical_json: bool,
+    pub no_trim: bool,
+    pub keep_quotes: bool,
+    pub expand_env: bool,
+    /// Delimiter between keys and values in `.conf` files, `=` if not set
+    pub delimiter: Option<String>,
+    pub line_format: LineFormat,
     pub key_template: String,
+    /// Key template given on the command line, `_template` directives of config files are ignored
+    pub key_template_fixed: bool,
+    /// Variables of the key template besides service, env and key
+    pub template_vars: HashMap<String, String>,
+    /// Allow rendered keys with empty path segments like `a//b`
+    pub allow_empty_segments: bool,
+    /// Publish one value to the path rendered by a template without `{key}`
+    pub single_value: bool,
+    pub require_reversible: bool,
     pub timeout: u64,
+    /// Timeout of each HTTP request to Consul in seconds, none if not given
+    pub consul_timeout: Option<u64>,
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds
+    pub retry_delay: u64,
+    pub write_order_index: bool,
+    pub write_version: bool,
+    pub write_manifest: bool,
+    /// Read back every written key and compare it with the written value
+    pub verify_writes: bool,
+    /// Maximum size of a value in bytes, the Consul default limit if not set
+    pub max_value_size: Option<usize>,
+    /// Gzip values larger than the compression threshold
+    pub compress: bool,
+    /// Minimum size of a value in bytes to compress, a default threshold if not set
+    pub compress_threshold: Option<usize>,
+    pub create_only: bool,
+    pub update_only: bool,
+    pub on_conflict: ConflictPolicy,
+    pub warn_empty_remote: bool,
+    pub warn_double_base64: bool,
+    pub prune: bool,
+    /// Ask on stdin before deleting keys when pruning
+    pub confirm: bool,
+    pub atomic: bool,
+    pub cas: bool,
+    pub cas_fail: CasFailPolicy,
+    pub flags: Option<u64>,
+    pub diff: bool,
+    pub output: OutputFormat,
+    pub batch_delete: bool,
+    pub max_changes: Option<usize>,
+    /// Number of config files published concurrently
+    pub jobs: usize,
+    /// Publish the remaining config files and datacenters after one of them fails
+    pub continue_on_error: bool,
+    /// Show a progress bar of published config files
+    pub progress: bool,
+    pub write_timestamps: bool,
+    pub shadow_template: Option<String>,
+    /// Consul key template of a session lock held on each service and env while publishing
+    pub lock_template: Option<String>,
+    /// Seconds to wait for a lock held by another publisher
+    pub lock_timeout: u64,
+    /// Cache of fingerprints of published config files, unchanged files are skipped if given
+    pub cache_file: Option<String>,
+    /// Directory of snapshots of Consul keys taken before publishing, no backups if not set
+    pub backup_dir: Option<String>,
+    /// Record written and deleted keys with their Consul indexes
+    pub audit: bool,
+    pub explain_skip: bool,
 }
@@ -8,8 +8,44 @@ pub enum Error {
     DuplicateKey(String),
     #[error("consul error: {0}")]
     Consul(#[from] consul::errors::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
     #[error("template error: {0}")]
     Template(String),
+    #[error("invalid arguments: {0}")]
+    Argument(String),
+    #[error("found {0} keys out of sync with Consul")]
+    Drift(usize),
+    #[error("found {0} invalid config files")]
+    Invalid(usize),
+    #[error("referenced key {0} not found")]
+    Reference(String),
+    #[error("values change shape for keys {0}")]
+    Conflict(String),
+    #[error("key {0} not found")]
+    NotFound(String),
+    #[error("cannot decode base64 value of key {0}")]
+    Decode(String),
+    #[error("cannot decompress value of key {0}")]
+    Decompress(String),
+    #[error("value of key {0} is not valid UTF-8")]
+    InvalidUtf8(String),
+    #[error("key {0} was modified concurrently")]
+    ConcurrentChange(String),
+    #[error("key {0} does not hold the written value: {1}")]
+    WriteMismatch(String, String),
+    #[error("file watch error: {0}")]
+    Watch(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("no config files to process: {0}")]
+    NoConfigs(String),
+    #[error("failed to publish {0} config files")]
+    FailedFiles(usize),
+    #[error("failed to publish to datacenters {0}")]
+    Datacenters(String),
+    #[error("lock {0} is held by another publisher")]
+    Locked(String),
     #[error("Consul is unreachable")]
     Unreachable,
     #[error("unknown error")]
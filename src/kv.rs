@@ -4,8 +4,10 @@ use std::collections::HashSet;
 use std::fmt;
 use std::io::BufRead;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use regex::Regex;
 use strfmt::strfmt;
 
 use crate::error::Error;
@@ -15,6 +17,9 @@ pub struct ServiceConfig {
     key_template: String,
     service: String,
     env: String,
+    /// Extra template variables
+    vars: HashMap<String, String>,
+    allow_empty_segments: bool,
 }
 
 impl fmt::Display for ServiceConfig {
@@ -29,38 +34,676 @@ impl ServiceConfig {
             key_template,
             service,
             env,
+            vars: HashMap::new(),
+            allow_empty_segments: false,
         }
     }
 
+    /// Same service config with extra template variables
+    pub fn with_vars(mut self, vars: HashMap<String, String>) -> ServiceConfig {
+        self.vars = vars;
+        self
+    }
+
+    /// Same service config accepting keys with empty path segments
+    pub fn with_empty_segments(mut self, allow: bool) -> ServiceConfig {
+        self.allow_empty_segments = allow;
+        self
+    }
+
+    /// Same service, env and variables with another key template
+    pub fn with_template(&self, key_template: String) -> ServiceConfig {
+        ServiceConfig::new(key_template, self.service.clone(), self.env.clone())
+            .with_vars(self.vars.clone())
+            .with_empty_segments(self.allow_empty_segments)
+    }
+
+    /// Service name
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Environment name
+    pub fn env(&self) -> &str {
+        &self.env
+    }
+
     /// Create full Consul key from simple key
     pub fn consul_key(&self, key: &str) -> Result<String, Error> {
-        let vars: HashMap<String, String> = HashMap::from([
+        let mut vars = self.vars.clone();
+        vars.extend([
             (String::from("service"), self.service.clone()),
             (String::from("env"), self.env.clone()),
             (String::from("key"), String::from(key)),
         ]);
 
         match strfmt(&self.key_template, &vars) {
+            // An empty service, env or key leaves a double slash, which is almost always a mistake
+            Ok(s) if s.contains("//") && !self.allow_empty_segments => Err(Error::Template(
+                format!("key {} has an empty path segment", s),
+            )),
             Ok(s) => Ok(s),
             Err(e) => Err(Error::Template(e.to_string())),
         }
     }
 }
 
+/// Suffix of a key whose value is a reference to another Consul key
+const REFERENCE_SUFFIX: &str = "@ref";
+
+/// Prefix of directives marking keys written without comparison, e.g. `_nocompare.key = true`
+const NO_COMPARE_PREFIX: &str = "_nocompare.";
+
+/// Config path standing for the standard input
+pub const STDIN_PATH: &str = "-";
+
+/// Default delimiter between keys and values in `.conf` files
+pub const DEFAULT_DELIMITER: &str = "=";
+
+/// Format of lines of line-based config files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineFormat {
+    /// `key = value` lines
+    #[default]
+    Conf,
+    /// Shell-sourceable `.env` lines with an optional `export` keyword and quoted values
+    Dotenv,
+}
+
+impl FromStr for LineFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "conf" => Ok(LineFormat::Conf),
+            "dotenv" => Ok(LineFormat::Dotenv),
+            _ => Err(format!("unknown line format {}", s)),
+        }
+    }
+}
+
+/// Syntax of lines of line-based config files
+#[derive(Debug, Clone, Copy)]
+pub struct LineSyntax<'a> {
+    /// Delimiter between keys and values
+    pub delimiter: &'a str,
+    pub format: LineFormat,
+}
+
+impl Default for LineSyntax<'_> {
+    fn default() -> Self {
+        LineSyntax {
+            delimiter: DEFAULT_DELIMITER,
+            format: LineFormat::default(),
+        }
+    }
+}
+
+/// Keyword of `.env` lines exporting a variable
+const EXPORT_KEYWORD: &str = "export ";
+
+/// Extensions of supported config files
+pub const CONFIG_EXTENSIONS: &[&str] = &["conf", "properties", "yaml", "yml", "json", "toml"];
+
+/// Prefix of directives marking keys with JSON values, e.g. `_json.key = true`
+const JSON_PREFIX: &str = "_json.";
+
+/// Prefix of directives setting Consul flags of keys, e.g. `_flags.key = 42`
+const FLAGS_PREFIX: &str = "_flags.";
+
+/// Directives of publishing settings of a config file, e.g. `_env = prod`
+const TEMPLATE_DIRECTIVE: &str = "_template";
+const SERVICE_DIRECTIVE: &str = "_service";
+const ENV_DIRECTIVE: &str = "_env";
+const PRUNE_DIRECTIVE: &str = "_prune";
+
+/// Directive line inlining another config file, e.g. `#include shared/db.inc`
+const INCLUDE_DIRECTIVE: &str = "#include ";
+
+/// Prefixes of INI-style and C-style whole-line comments
+const COMMENT_PREFIXES: [&str; 2] = [";", "//"];
+
+/// UTF-8 byte order mark
+const BOM: char = '\u{feff}';
+
+/// Table with keys in rows and envs in columns
+struct Matrix {
+    envs: Vec<String>,
+    rows: Vec<(String, Vec<String>)>,
+}
+
+/// Publishing settings carried by a config file, command line options take precedence
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileSettings {
+    /// Key template from `_template`
+    pub template: Option<String>,
+    /// Service name from `_service`
+    pub service: Option<String>,
+    /// Env name from `_env`
+    pub env: Option<String>,
+    /// Removal of keys missing in the file from `_prune`
+    pub prune: Option<bool>,
+    /// Unknown directives, ignored
+    pub unknown: Vec<String>,
+}
+
+impl FileSettings {
+    /// Collect settings from directive lines
+    fn from_lines(lines: &[(String, String)]) -> Result<Self, Error> {
+        let mut settings = FileSettings::default();
+        for (key, value) in lines {
+            match key.as_str() {
+                TEMPLATE_DIRECTIVE => settings.template = Some(value.clone()),
+                SERVICE_DIRECTIVE => settings.service = Some(value.clone()),
+                ENV_DIRECTIVE => settings.env = Some(value.clone()),
+                PRUNE_DIRECTIVE => {
+                    settings.prune = Some(match value.as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(Error::ConfigFormat(format!(
+                                "directive {} must be true or false",
+                                key
+                            )))
+                        }
+                    })
+                }
+                _ if key.starts_with('_')
+                    && ![NO_COMPARE_PREFIX, JSON_PREFIX, FLAGS_PREFIX]
+                        .iter()
+                        .any(|prefix| key.starts_with(prefix)) =>
+                {
+                    settings.unknown.push(key.clone());
+                }
+                _ => {}
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Override settings with those set in `other`
+    fn merge(&mut self, other: FileSettings) {
+        self.template = other.template.or(self.template.take());
+        self.service = other.service.or(self.service.take());
+        self.env = other.env.or(self.env.take());
+        self.prune = other.prune.or(self.prune.take());
+        self.unknown.extend(other.unknown);
+    }
+}
+
 /// Represents KV configuration file
 pub struct KVConfig {
     kv: HashMap<String, String>,
+    /// Keys with values copied from other Consul keys
+    references: HashMap<String, String>,
+    /// Keys in the order of appearance in the file
+    order: Vec<String>,
+    /// Keys written on every run without comparing with Consul
+    no_compare: HashSet<String>,
+    /// Keys with JSON values
+    json: HashSet<String>,
+    /// Consul flags of keys
+    flags: HashMap<String, u64>,
+    /// Publishing settings of the file
+    settings: FileSettings,
 }
 
 impl KVConfig {
-    /// Create KV config from the config file
+    /// Create KV config from the config file, parsed according to its extension
     pub fn new(file_path: &Path) -> Result<Self, Error> {
+        Self::with_syntax(file_path, LineSyntax::default())
+    }
+
+    /// Create KV config from the config file, parsing line-based files with the given syntax
+    pub fn with_syntax(file_path: &Path, syntax: LineSyntax) -> Result<Self, Error> {
+        if file_path == Path::new(STDIN_PATH) {
+            let mut kv_config = Self::from_reader_with_syntax(std::io::stdin().lock(), syntax)?;
+            kv_config.load_file_values(Path::new("."))?;
+            return Ok(kv_config);
+        }
+        let mut kv_config = match Self::structured_file(file_path)? {
+            Some(kv_config) => kv_config,
+            None => Self::from_pairs(Self::read_pairs(file_path, syntax, &mut Vec::new())?)?,
+        };
+        kv_config.load_file_values(file_path.parent().unwrap_or_else(|| Path::new(".")))?;
+        Ok(kv_config)
+    }
+
+    /// Read publishing settings of a config file from its directive lines only,
+    /// without loading `@file` values or included files
+    pub fn read_settings(file_path: &Path, syntax: LineSyntax) -> Result<FileSettings, Error> {
+        if let Some(kv_config) = Self::structured_file(file_path)? {
+            return Ok(kv_config.settings);
+        }
         let file = std::fs::File::open(file_path).map_err(Error::ConfigFile)?;
-        let res_lines: Result<Vec<_>, _> = std::io::BufReader::new(file)
+        let directives: Vec<(String, String)> = Self::parse_lines(
+            std::io::BufReader::new(file),
+            Some(file_path),
+            syntax,
+            |_| Ok(Vec::new()),
+        )?
+        .into_iter()
+        .filter(|(key, _)| key.starts_with('_'))
+        .collect();
+        FileSettings::from_lines(&directives)
+    }
+
+    /// Parse a YAML, JSON or TOML config file, None for line-based files
+    fn structured_file(file_path: &Path) -> Result<Option<Self>, Error> {
+        let parse = match Self::config_extension(file_path) {
+            Some("yaml") | Some("yml") => Self::from_yaml,
+            Some("json") => Self::from_json,
+            Some("toml") => Self::from_toml,
+            _ => return Ok(None),
+        };
+        let content = std::fs::read_to_string(file_path).map_err(Error::ConfigFile)?;
+        parse(content.strip_prefix(BOM).unwrap_or(&content)).map(Some)
+    }
+
+    /// Replace `@path` values with contents of files relative to `base_dir`, unescape `@@` to `@`
+    fn load_file_values(&mut self, base_dir: &Path) -> Result<(), Error> {
+        for (key, value) in self.kv.iter_mut() {
+            if self.references.contains_key(key) {
+                continue;
+            }
+            if let Some(literal) = value.strip_prefix("@@") {
+                *value = format!("@{}", literal);
+            } else if let Some(path) = value.strip_prefix('@') {
+                let path = base_dir.join(path.trim());
+                debug!("Load value of key {} from {}", key, path.display());
+                *value = std::fs::read_to_string(&path).map_err(|err| {
+                    Error::ConfigFile(std::io::Error::new(
+                        err.kind(),
+                        format!("{} referenced by key {}: {}", path.display(), key, err),
+                    ))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Extension of a config file, looking through the `.local` suffix of overrides
+    fn config_extension(file_path: &Path) -> Option<&str> {
+        let extension = file_path.extension().and_then(|s| s.to_str());
+        if extension == Some("local") {
+            file_path
+                .file_stem()
+                .map(Path::new)
+                .and_then(|stem| stem.extension())
+                .and_then(|s| s.to_str())
+        } else {
+            extension
+        }
+    }
+
+    /// Create KV config from a YAML document
+    fn from_yaml(content: &str) -> Result<Self, Error> {
+        let value: serde_json::Value = serde_yaml::from_str(content)
+            .map_err(|err| Error::ConfigFormat(format!("invalid YAML: {}", err)))?;
+        Self::from_value(value)
+    }
+
+    /// Create KV config from a JSON document
+    fn from_json(content: &str) -> Result<Self, Error> {
+        let value: serde_json::Value = serde_json::from_str(content)
+            .map_err(|err| Error::ConfigFormat(format!("invalid JSON: {}", err)))?;
+        Self::from_value(value)
+    }
+
+    /// Create KV config from a TOML document, tables are nested mappings
+    fn from_toml(content: &str) -> Result<Self, Error> {
+        let table: toml::Table = toml::from_str(content)
+            .map_err(|err| Error::ConfigFormat(format!("invalid TOML: {}", err)))?;
+        Self::from_value(Self::toml_to_json(toml::Value::Table(table)))
+    }
+
+    /// Convert a TOML value to JSON, datetimes become strings in TOML format
+    fn toml_to_json(value: toml::Value) -> serde_json::Value {
+        match value {
+            toml::Value::String(value) => serde_json::Value::String(value),
+            toml::Value::Integer(value) => serde_json::Value::from(value),
+            toml::Value::Float(value) => serde_json::Number::from_f64(value)
+                .map(serde_json::Value::Number)
+                .unwrap_or_else(|| serde_json::Value::String(value.to_string())),
+            toml::Value::Boolean(value) => serde_json::Value::Bool(value),
+            toml::Value::Datetime(value) => serde_json::Value::String(value.to_string()),
+            toml::Value::Array(values) => {
+                serde_json::Value::Array(values.into_iter().map(Self::toml_to_json).collect())
+            }
+            toml::Value::Table(table) => serde_json::Value::Object(
+                table
+                    .into_iter()
+                    .map(|(key, value)| (key, Self::toml_to_json(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Create KV config from a nested mapping, joining nested keys with dots
+    fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        let mut pairs = Vec::new();
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    Self::flatten_value(key, value, &mut pairs)?;
+                }
+            }
+            _ => {
+                return Err(Error::ConfigFormat(String::from(
+                    "top level must be a mapping",
+                )))
+            }
+        }
+        Self::from_pairs(pairs)
+    }
+
+    /// Add a scalar value or flatten a nested mapping into pairs
+    fn flatten_value(
+        key: String,
+        value: serde_json::Value,
+        pairs: &mut Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        let value = match value {
+            serde_json::Value::Object(map) => {
+                for (nested_key, nested_value) in map {
+                    Self::flatten_value(format!("{}.{}", key, nested_key), nested_value, pairs)?;
+                }
+                return Ok(());
+            }
+            serde_json::Value::Array(_) => {
+                return Err(Error::ConfigFormat(format!(
+                    "arrays are not supported, found in key {}",
+                    key
+                )))
+            }
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::Bool(value) => value.to_string(),
+            serde_json::Value::Number(value) => value.to_string(),
+            serde_json::Value::String(value) => value.trim().to_string(),
+        };
+        if value.is_empty() {
+            return Err(Error::ConfigFormat(format!("Empty value in key {}", key)));
+        }
+        pairs.push((key, value));
+        Ok(())
+    }
+
+    /// Create KV config from a reader with config lines, includes are not supported
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
+        Self::from_reader_with_syntax(reader, LineSyntax::default())
+    }
+
+    /// Create KV config from a reader with config lines of the given syntax
+    pub fn from_reader_with_syntax<R: BufRead>(
+        reader: R,
+        syntax: LineSyntax,
+    ) -> Result<Self, Error> {
+        Self::from_pairs(Self::parse_lines(reader, None, syntax, |include| {
+            Err(Error::ConfigFormat(format!(
+                "cannot include {} without a config file path",
+                include
+            )))
+        })?)
+    }
+
+    /// Read key-value pairs of a config file, expanding includes relative to its directory.
+    /// `including` holds files being read to detect include cycles.
+    fn read_pairs(
+        file_path: &Path,
+        syntax: LineSyntax,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let canonical_path = file_path.canonicalize().map_err(Error::ConfigFile)?;
+        if including.contains(&canonical_path) {
+            return Err(Error::ConfigFormat(format!(
+                "include cycle through {}",
+                file_path.display()
+            )));
+        }
+        including.push(canonical_path);
+        let file = std::fs::File::open(file_path).map_err(Error::ConfigFile)?;
+        let base_dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+        let pairs = Self::parse_lines(
+            std::io::BufReader::new(file),
+            Some(file_path),
+            syntax,
+            |include| Self::read_pairs(&base_dir.join(include), syntax, including),
+        )?;
+        including.pop();
+        Ok(pairs)
+    }
+
+    /// Parse lines into key-value pairs, replacing `#include path` lines with pairs returned by `include`.
+    /// Keys after a `[section]` header are prefixed with `section/` until the next header or `[]`.
+    /// Errors are prefixed with `file_path:line`, or with the line number if there is no file.
+    fn parse_lines<R, F>(
+        reader: R,
+        file_path: Option<&Path>,
+        syntax: LineSyntax,
+        mut include: F,
+    ) -> Result<Vec<(String, String)>, Error>
+    where
+        R: BufRead,
+        F: FnMut(&str) -> Result<Vec<(String, String)>, Error>,
+    {
+        let location = |number: usize| match file_path {
+            Some(file_path) => format!("{}:{}", file_path.display(), number + 1),
+            None => format!("line {}", number + 1),
+        };
+        let mut lines = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|err| {
+                Error::ConfigFormat(format!("{}: cannot read line: {}", location(number), err))
+            })?;
+            // Files saved on Windows may start with a byte order mark and end lines with CRLF
+            let line = if number == 0 {
+                line.strip_prefix(BOM).unwrap_or(&line)
+            } else {
+                &line
+            };
+            lines.push((number, line.strip_suffix('\r').unwrap_or(line).to_string()));
+        }
+        let mut pairs = Vec::new();
+        let mut section = String::new();
+        for (number, line) in Self::join_continued_lines(lines) {
+            let line = line.as_str();
+            if Self::is_comment_line(line) && !line.trim().starts_with(INCLUDE_DIRECTIVE) {
+                continue;
+            }
+            if let Some(name) = Self::section_header(line, syntax.delimiter) {
+                section = name;
+                continue;
+            }
+            let line_pairs = match line.trim().strip_prefix(INCLUDE_DIRECTIVE) {
+                Some(path) => include(path.trim())?,
+                // Blank lines are common in .env files
+                None if syntax.format == LineFormat::Dotenv && line.trim().is_empty() => continue,
+                None => vec![Self::parse_line(line, syntax).map_err(|err| match err {
+                    Error::ConfigFormat(message) => {
+                        Error::ConfigFormat(format!("{}: {}", location(number), message))
+                    }
+                    err => err,
+                })?],
+            };
+            pairs.extend(
+                line_pairs
+                    .into_iter()
+                    .map(|(key, value)| (Self::section_key(&section, key), value)),
+            );
+        }
+        Ok(pairs)
+    }
+
+    /// Join lines ending with a backslash with the next line without its leading whitespace.
+    /// A trailing `\\` stands for a literal backslash and ends the line.
+    /// Joined lines keep the number of their first line for error messages.
+    fn join_continued_lines(lines: Vec<(usize, String)>) -> Vec<(usize, String)> {
+        let mut joined = Vec::new();
+        let mut continued: Option<(usize, String)> = None;
+        for (number, line) in lines {
+            let (number, mut line) = match continued.take() {
+                Some((first, mut head)) => {
+                    head.push_str(line.trim_start());
+                    (first, head)
+                }
+                // a comment ending with a backslash does not continue to the next line
+                None if Self::is_comment_line(&line) => {
+                    joined.push((number, line));
+                    continue;
+                }
+                None => (number, line),
+            };
+            let backslashes = line.len() - line.trim_end_matches('\\').len();
+            line.truncate(line.len() - backslashes.div_ceil(2));
+            if backslashes % 2 == 1 {
+                continued = Some((number, line));
+            } else {
+                joined.push((number, line));
+            }
+        }
+        joined.extend(continued);
+        joined
+    }
+
+    /// Check if a line is a whole-line comment starting with `#`, `;` or `//`
+    fn is_comment_line(line: &str) -> bool {
+        let line = line.trim_start();
+        line.starts_with('#')
+            || COMMENT_PREFIXES
+                .iter()
+                .any(|prefix| line.starts_with(prefix))
+    }
+
+    /// Section name of a `[section]` header line without the delimiter, empty for `[]`
+    fn section_header(line: &str, delimiter: &str) -> Option<String> {
+        let name = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+        if name.contains(delimiter) {
+            return None;
+        }
+        Some(name.trim().trim_matches('/').to_string())
+    }
+
+    /// Prefix a key with the section, keeping directives such as `_flags.key` recognizable
+    fn section_key(section: &str, key: String) -> String {
+        if section.is_empty() {
+            return key;
+        }
+        for prefix in [NO_COMPARE_PREFIX, JSON_PREFIX, FLAGS_PREFIX] {
+            if let Some(name) = key.strip_prefix(prefix) {
+                return format!("{}{}/{}", prefix, section, name);
+            }
+        }
+        // Other keys starting with an underscore and comments are skipped anyway
+        if key.starts_with('_') || key.starts_with('#') {
+            return key;
+        }
+        format!("{}/{}", section, key)
+    }
+
+    /// Create KV config from an env column of a matrix file
+    pub fn from_matrix(file_path: &Path, env: &str) -> Result<Self, Error> {
+        Self::from_matrix_column(Self::read_matrix(file_path)?, env)
+    }
+
+    /// Env columns of a matrix file
+    pub fn matrix_envs(file_path: &Path) -> Result<Vec<String>, Error> {
+        Ok(Self::read_matrix(file_path)?.envs)
+    }
+
+    /// Read a matrix file, tab-separated for `.tsv` extension and comma-separated otherwise
+    fn read_matrix(file_path: &Path) -> Result<Matrix, Error> {
+        let delimiter = match file_path.extension().and_then(|s| s.to_str()) {
+            Some("tsv") => '\t',
+            _ => ',',
+        };
+        let file = std::fs::File::open(file_path).map_err(Error::ConfigFile)?;
+        Self::parse_matrix(std::io::BufReader::new(file), delimiter)
+    }
+
+    /// Parse a matrix with a header row of `key` and env names followed by rows of key and values
+    fn parse_matrix<R: BufRead>(reader: R, delimiter: char) -> Result<Matrix, Error> {
+        let split = |line: &str| -> Vec<String> {
+            line.split(delimiter)
+                .map(|cell| cell.trim().to_string())
+                .collect()
+        };
+        let mut lines = reader
             .lines()
-            .map(|line| Self::handle_line(&line.unwrap()))
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()));
+        let header = match lines.next() {
+            Some((_, line)) => split(&line.map_err(Error::ConfigFile)?),
+            None => return Err(Error::ConfigFormat(String::from("Empty matrix"))),
+        };
+        if header[0] != "key" {
+            return Err(Error::ConfigFormat(String::from(
+                "Matrix header must start with a key column",
+            )));
+        }
+        let envs: Vec<String> = header[1..].to_vec();
+        if envs.is_empty() || envs.iter().any(|env| env.is_empty()) {
+            return Err(Error::ConfigFormat(String::from(
+                "Matrix header must name env columns",
+            )));
+        }
+        let mut unique_envs = HashSet::new();
+        if let Some(env) = envs.iter().find(|env| !unique_envs.insert(*env)) {
+            return Err(Error::ConfigFormat(format!(
+                "Duplicated env column {}",
+                env
+            )));
+        }
+        let mut rows = vec![];
+        for (index, line) in lines {
+            let cells = split(&line.map_err(Error::ConfigFile)?);
+            if cells.len() != header.len() {
+                return Err(Error::ConfigFormat(format!(
+                    "Matrix row {} has {} cells, expected {}",
+                    index + 1,
+                    cells.len(),
+                    header.len()
+                )));
+            }
+            let mut cells = cells.into_iter();
+            let key = cells.next().unwrap_or_default();
+            if key.is_empty() {
+                return Err(Error::ConfigFormat("Empty key".to_string()));
+            }
+            rows.push((key, cells.collect()));
+        }
+        Ok(Matrix { envs, rows })
+    }
+
+    /// Create KV config from an env column of a matrix, skipping empty cells
+    fn from_matrix_column(matrix: Matrix, env: &str) -> Result<Self, Error> {
+        let column = matrix
+            .envs
+            .iter()
+            .position(|matrix_env| matrix_env == env)
+            .ok_or_else(|| Error::ConfigFormat(format!("No env column {} in matrix", env)))?;
+        let pairs = matrix
+            .rows
+            .into_iter()
+            .map(|(key, mut values)| (key, values.swap_remove(column)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        Self::from_pairs(pairs)
+    }
+
+    /// Create KV config from parsed key-value pairs
+    fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, Error> {
+        let mut references = HashMap::new();
+        let lines: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| match key.strip_suffix(REFERENCE_SUFFIX) {
+                Some(name) => {
+                    let name = name.trim_end().to_string();
+                    references.insert(name.clone(), value.clone());
+                    (name, value)
+                }
+                None => (key, value),
+            })
             .collect();
-        let lines: Vec<_> = res_lines?;
         let mut keys = HashSet::new();
         // Do not allow duplicate keys
         for (key, _) in &lines {
@@ -68,42 +711,462 @@ impl KVConfig {
                 return Err(Error::DuplicateKey(key.clone()));
             }
         }
-        let hash_map: HashMap<String, String> = lines
+        let no_compare = Self::directive_keys(&lines, NO_COMPARE_PREFIX)?;
+        let json = Self::directive_keys(&lines, JSON_PREFIX)?;
+        let flags = Self::directive_flags(&lines)?;
+        let settings = FileSettings::from_lines(&lines)?;
+        let lines: Vec<(String, String)> = lines
             .into_iter()
             // skip items starting with underscore
             .filter(|(k, _)| !k.starts_with('_') && !k.starts_with('#'))
             .collect();
+        let order: Vec<String> = lines.iter().map(|(k, _)| k.clone()).collect();
+        let hash_map: HashMap<String, String> = lines.into_iter().collect();
         debug!("Read {} keys", hash_map.len());
-        Ok(KVConfig { kv: hash_map })
+        Ok(KVConfig {
+            kv: hash_map,
+            references,
+            order,
+            no_compare,
+            json,
+            flags,
+            settings,
+        })
     }
 
-    /// Find keys that are in `existing_keys` but not in this config
+    /// Collect keys enabled by `<prefix>key = true` directives
+    fn directive_keys(lines: &[(String, String)], prefix: &str) -> Result<HashSet<String>, Error> {
+        let mut result = HashSet::new();
+        for (key, value) in lines {
+            if let Some(name) = key.strip_prefix(prefix) {
+                match value.as_str() {
+                    "true" => {
+                        result.insert(name.to_string());
+                    }
+                    "false" => {}
+                    _ => {
+                        return Err(Error::ConfigFormat(format!(
+                            "directive {} must be true or false",
+                            key
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Collect key flags set by `_flags.key = N` directives
+    fn directive_flags(lines: &[(String, String)]) -> Result<HashMap<String, u64>, Error> {
+        let mut result = HashMap::new();
+        for (key, value) in lines {
+            if let Some(name) = key.strip_prefix(FLAGS_PREFIX) {
+                let flags = value.parse().map_err(|_| {
+                    Error::ConfigFormat(format!("directive {} must be an unsigned integer", key))
+                })?;
+                result.insert(name.to_string(), flags);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Substitute `${VAR}` and `${VAR:-default}` in values with variables returned by `lookup`,
+    /// `$$` stands for a literal `$`
+    pub fn expand_env<F>(&mut self, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        for (key, value) in self.kv.iter_mut() {
+            *value = Self::expand_value(key, value, &lookup)?;
+        }
+        Ok(())
+    }
+
+    /// Substitute variables in one value of a key
+    fn expand_value<F>(key: &str, value: &str, lookup: &F) -> Result<String, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut result = String::new();
+        let mut rest = value;
+        while let Some(pos) = rest.find('$') {
+            result.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            if let Some(after) = rest.strip_prefix("$$") {
+                result.push('$');
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("${") {
+                let end = after.find('}').ok_or_else(|| {
+                    Error::ConfigFormat(format!("unterminated variable in key {}", key))
+                })?;
+                let (name, default) = match after[..end].split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (&after[..end], None),
+                };
+                match lookup(name).or_else(|| default.map(String::from)) {
+                    Some(expanded) => result.push_str(&expanded),
+                    None => {
+                        return Err(Error::ConfigFormat(format!(
+                            "environment variable {} used in key {} is not set",
+                            name, key
+                        )))
+                    }
+                }
+                rest = &after[end + 1..];
+            } else {
+                result.push('$');
+                rest = &rest[1..];
+            }
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Parse a JSON value of a key
+    fn parse_json_value(key: &str, value: &str) -> Result<serde_json::Value, Error> {
+        serde_json::from_str(value).map_err(|err| {
+            Error::ConfigFormat(format!(
+                "invalid JSON in key {} at line {} column {}: {}",
+                key,
+                err.line(),
+                err.column(),
+                err
+            ))
+        })
+    }
+
+    /// Check that every key matches `pattern`, naming the first offending key in file order
+    pub fn check_keys(&self, pattern: &Regex) -> Result<(), Error> {
+        match self.order.iter().find(|key| !pattern.is_match(key)) {
+            Some(key) => Err(Error::ConfigFormat(format!(
+                "key {} does not match key pattern {}",
+                key, pattern
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check JSON values of keys marked by directives or matching `pattern`,
+    /// rewriting them in canonical compact form if `canonical` is set
+    pub fn check_json_values(
+        &mut self,
+        pattern: Option<&Regex>,
+        canonical: bool,
+    ) -> Result<(), Error> {
+        for (key, value) in self.kv.iter_mut() {
+            let is_json =
+                self.json.contains(key) || pattern.map(|re| re.is_match(key)).unwrap_or(false);
+            if !is_json {
+                continue;
+            }
+            let json = Self::parse_json_value(key, value)?;
+            if canonical {
+                *value = serde_json::to_string(&json)
+                    .map_err(|err| Error::ConfigFormat(err.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stable hash of sorted key-value pairs, changes whenever any key or value changes
+    pub fn content_hash(&self) -> String {
+        Self::pairs_hash(
+            self.kv
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        )
+    }
+
+    /// Stable hash of `_nocompare.`, `_json.` and `_flags.` directives of keys
+    pub fn directives_hash(&self) -> String {
+        let directives = self
+            .no_compare
+            .iter()
+            .map(|key| (format!("{}{}", NO_COMPARE_PREFIX, key), String::new()))
+            .chain(
+                self.json
+                    .iter()
+                    .map(|key| (format!("{}{}", JSON_PREFIX, key), String::new())),
+            )
+            .chain(
+                self.flags
+                    .iter()
+                    .map(|(key, flags)| (format!("{}{}", FLAGS_PREFIX, key), flags.to_string())),
+            );
+        Self::pairs_hash(directives)
+    }
+
+    /// 64-bit FNV-1a hash of pairs sorted by key, stable across runs and platforms
+    fn pairs_hash(pairs: impl Iterator<Item = (String, String)>) -> String {
+        let mut pairs: Vec<(String, String)> = pairs.collect();
+        pairs.sort();
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for (key, value) in pairs {
+            for part in [key.as_bytes(), &[0], value.as_bytes(), &[0]] {
+                for byte in part {
+                    hash ^= *byte as u64;
+                    hash = hash.wrapping_mul(0x100000001b3);
+                }
+            }
+        }
+        format!("{:016x}", hash)
+    }
+
+    /// Keys in the order of appearance in the config file
+    pub fn ordered_keys(&self) -> &[String] {
+        &self.order
+    }
+
+    /// Keys marked to be written without comparison
+    pub fn no_compare_keys(&self) -> &HashSet<String> {
+        &self.no_compare
+    }
+
+    /// Consul flags of a key set by a directive
+    pub fn flags(&self, key: &str) -> Option<u64> {
+        self.flags.get(key).copied()
+    }
+
+    /// Publishing settings set by directives of the file
+    pub fn settings(&self) -> &FileSettings {
+        &self.settings
+    }
+
+    /// Merge another KV config over this one, values of the other config win
+    pub fn merge(&mut self, other: KVConfig) {
+        self.settings.merge(other.settings);
+        self.no_compare.extend(other.no_compare);
+        self.json.extend(other.json);
+        self.flags.extend(other.flags);
+        for key in other.order {
+            if !self.kv.contains_key(&key) {
+                self.order.push(key.clone());
+            }
+            match other.references.get(&key) {
+                Some(reference) => self.references.insert(key.clone(), reference.clone()),
+                None => self.references.remove(&key),
+            };
+            if let Some(value) = other.kv.get(&key) {
+                self.kv.insert(key, value.clone());
+            }
+        }
+    }
+
+    /// Keep only keys for which `selected` returns true, dropping directives of other keys
+    pub fn retain_keys<F>(&mut self, selected: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.kv.retain(|key, _| selected(key));
+        self.references.retain(|key, _| selected(key));
+        self.order.retain(|key| selected(key));
+        self.no_compare.retain(|key| selected(key));
+        self.json.retain(|key| selected(key));
+        self.flags.retain(|key, _| selected(key));
+    }
+
+    /// Service and env groups of a merged config in the order of appearance, with keys
+    /// split by `pattern` into named groups `service`, `env` and `key`
+    pub fn merged_groups(&self, pattern: &Regex) -> Result<Vec<(String, String)>, Error> {
+        let mut groups = Vec::new();
+        for key in &self.order {
+            let (service, env, _) = Self::split_merged_key(pattern, key)?;
+            if !groups.contains(&(service.clone(), env.clone())) {
+                groups.push((service, env));
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Keys of one service and env group of a merged config without the group prefix
+    pub fn merged_group(
+        mut self,
+        pattern: &Regex,
+        service: &str,
+        env: &str,
+    ) -> Result<Self, Error> {
+        self.retain_keys(|key| {
+            matches!(Self::split_merged_key(pattern, key),
+                Ok((key_service, key_env, _)) if key_service == service && key_env == env)
+        });
+        self.rename_keys(|key| Self::split_merged_key(pattern, key).map(|(_, _, key)| key))?;
+        Ok(self)
+    }
+
+    /// Split a key of a merged config into service, env and key
+    fn split_merged_key(pattern: &Regex, key: &str) -> Result<(String, String, String), Error> {
+        let invalid = || {
+            Error::ConfigFormat(format!(
+                "key {} does not match merged key pattern {}",
+                key, pattern
+            ))
+        };
+        let captures = pattern.captures(key).ok_or_else(invalid)?;
+        let group = |name| {
+            captures
+                .name(name)
+                .map(|part| part.as_str().to_string())
+                .filter(|part| !part.is_empty())
+                .ok_or_else(invalid)
+        };
+        Ok((group("service")?, group("env")?, group("key")?))
+    }
+
+    /// Rename every key and its directives with `rename`
+    pub fn rename_keys<F>(&mut self, rename: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Result<String, Error>,
+    {
+        let rename_set = |keys: &HashSet<String>| {
+            keys.iter()
+                .map(|key| rename(key))
+                .collect::<Result<HashSet<String>, Error>>()
+        };
+        self.kv = self
+            .kv
+            .drain()
+            .map(|(key, value)| Ok((rename(&key)?, value)))
+            .collect::<Result<_, Error>>()?;
+        self.references = self
+            .references
+            .drain()
+            .map(|(key, reference)| Ok((rename(&key)?, reference)))
+            .collect::<Result<_, Error>>()?;
+        self.order = self
+            .order
+            .iter()
+            .map(|key| rename(key))
+            .collect::<Result<_, Error>>()?;
+        self.no_compare = rename_set(&self.no_compare)?;
+        self.json = rename_set(&self.json)?;
+        self.flags = self
+            .flags
+            .drain()
+            .map(|(key, flags)| Ok((rename(&key)?, flags)))
+            .collect::<Result<_, Error>>()?;
+        Ok(())
+    }
+
+    /// Replace values of reference keys with values of referenced Consul keys
+    pub fn resolve_references<F>(&mut self, fetch: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Result<String, Error>,
+    {
+        for (key, reference) in &self.references {
+            let value = fetch(reference)?;
+            debug!("Resolved key {} from {}", key, reference);
+            self.kv.insert(key.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// Find keys that are in `existing_keys` but not in this config,
+    /// comparing keys as they are put into the key template
     pub fn missing_keys(&self, existing_keys: &HashSet<String>) -> HashSet<String> {
+        let keys: HashSet<&str> = self.kv.keys().map(|key| key.trim_matches(' ')).collect();
         existing_keys
             .iter()
-            .filter_map(|existing_key| {
-                if self.kv.contains_key(existing_key) {
-                    None
-                } else {
-                    Some(existing_key.clone())
-                }
-            })
+            .filter(|existing_key| !keys.contains(existing_key.as_str()))
+            .cloned()
             .collect()
     }
 
-    /// Parse one key-value from the config line
-    fn handle_line(line: &str) -> Result<(String, String), Error> {
+    /// Parse one key-value from the config line according to the syntax
+    fn parse_line(line: &str, syntax: LineSyntax) -> Result<(String, String), Error> {
+        match syntax.format {
+            LineFormat::Conf => Self::handle_line(line, syntax.delimiter),
+            LineFormat::Dotenv => Self::handle_dotenv_line(line, syntax.delimiter),
+        }
+    }
+
+    /// Parse one key-value from a `.env` line, stripping the `export` keyword and unquoting the value.
+    /// Double-quoted values unescape `\"`, `\\`, `\$` and `\n`, single-quoted values are literal.
+    fn handle_dotenv_line(line: &str, delimiter: &str) -> Result<(String, String), Error> {
+        let line = line.trim_start();
+        let line = line.strip_prefix(EXPORT_KEYWORD).unwrap_or(line);
         let (k, v) = line
-            .split_once('=')
+            .split_once(delimiter)
             .ok_or_else(|| Error::ConfigFormat(String::from("No delimiter found")))?;
+        let k = k.trim();
         if k.is_empty() {
             return Err(Error::ConfigFormat("Empty key".to_string()));
         }
-        if v.is_empty() {
+        let v = v.trim_start();
+        let (value, rest) = match v.chars().next() {
+            Some(quote @ ('"' | '\'')) => Self::unquote(&v[1..], quote)?,
+            _ => (Self::strip_comment(v).trim().to_string(), ""),
+        };
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(Error::ConfigFormat(format!(
+                "unexpected text after quoted value: {}",
+                rest
+            )));
+        }
+        if value.is_empty() {
+            return Err(Error::ConfigFormat("Empty value".to_string()));
+        }
+        Ok((k.to_string(), value))
+    }
+
+    /// Read a quoted value up to the closing `quote`, returns the value and the rest of the line
+    fn unquote(value: &str, quote: char) -> Result<(String, &str), Error> {
+        let mut result = String::new();
+        let mut chars = value.char_indices();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                c if c == quote => return Ok((result, &value[pos + 1..])),
+                '\\' if quote == '"' => match chars.next() {
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, escaped @ ('"' | '\\' | '$'))) => result.push(escaped),
+                    Some((_, other)) => {
+                        result.push('\\');
+                        result.push(other);
+                    }
+                    None => break,
+                },
+                c => result.push(c),
+            }
+        }
+        Err(Error::ConfigFormat(format!("unterminated {} quote", quote)))
+    }
+
+    /// Parse one key-value from the config line, split at the first `delimiter`
+    fn handle_line(line: &str, delimiter: &str) -> Result<(String, String), Error> {
+        let (k, v) = line
+            .split_once(delimiter)
+            .ok_or_else(|| Error::ConfigFormat(String::from("No delimiter found")))?;
+        if k.is_empty() {
+            return Err(Error::ConfigFormat("Empty key".to_string()));
+        }
+        let v = Self::strip_comment(v);
+        if v.trim().is_empty() {
             return Err(Error::ConfigFormat("Empty value".to_string()));
         }
         Ok((k.trim().to_string(), v.trim().to_string()))
     }
+
+    /// Strip a trailing `# comment` from a value, keeping `#` inside double quotes or escaped as `\#`
+    fn strip_comment(value: &str) -> String {
+        let mut result = String::new();
+        let mut quoted = false;
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'#') => {
+                    result.push('#');
+                    chars.next();
+                }
+                '#' if !quoted => break,
+                '"' => {
+                    quoted = !quoted;
+                    result.push(c);
+                }
+                _ => result.push(c),
+            }
+        }
+        result
+    }
 }
 
 // Allow to construct iterator for KVConfig
@@ -123,22 +1186,671 @@ mod tests {
     #[test]
     fn test_parse_kv_line() {
         assert_eq!(
-            KVConfig::handle_line("foo=bar").unwrap(),
+            KVConfig::handle_line("foo=bar", "=").unwrap(),
             ("foo".to_string(), "bar".to_string())
         );
         assert_eq!(
-            KVConfig::handle_line("foo = bar ").unwrap(),
+            KVConfig::handle_line("foo = bar ", "=").unwrap(),
             ("foo".to_string(), "bar".to_string())
         );
-        assert!(KVConfig::handle_line("foo=").is_err());
-        assert!(KVConfig::handle_line("=bar").is_err());
-        assert!(KVConfig::handle_line("foo").is_err());
+        assert!(KVConfig::handle_line("foo=", "=").is_err());
+        assert!(KVConfig::handle_line("=bar", "=").is_err());
+        assert!(KVConfig::handle_line("foo", "=").is_err());
         assert_eq!(
-            KVConfig::handle_line("foo=bar=baz").unwrap(),
+            KVConfig::handle_line("foo=bar=baz", "=").unwrap(),
             ("foo".to_string(), "bar=baz".to_string())
         );
     }
 
+    #[test]
+    fn test_parse_sections() {
+        let kv_config = KVConfig::from_reader(
+            "name = svc\n[database]\nhost = db\nport = 5432\n_flags.port = 7\n\
+             [ cache/redis ]\nhost = redis\n[]\ntimeout = 30\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(kv_config.get("name").unwrap(), "svc");
+        assert_eq!(kv_config.get("database/host").unwrap(), "db");
+        assert_eq!(kv_config.get("database/port").unwrap(), "5432");
+        assert_eq!(kv_config.flags("database/port"), Some(7));
+        assert_eq!(kv_config.get("cache/redis/host").unwrap(), "redis");
+        assert_eq!(kv_config.get("timeout").unwrap(), "30");
+        assert_eq!(kv_config.get("host"), None);
+        // Commented-out lines in a section are not keys
+        let kv_config =
+            KVConfig::from_reader("[database]\nhost = db\n# port = 5432\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["database/host"]);
+        // Keys are compared fully qualified
+        assert!(KVConfig::from_reader("[db]\nhost = a\n[]\ndb/host = b\n".as_bytes()).is_err());
+        assert!(KVConfig::from_reader("[a]\nhost = 1\n[b]\nhost = 2\n".as_bytes()).is_ok());
+        // A key in brackets with a value is not a header
+        let kv_config = KVConfig::from_reader("[key] = [1, 2]\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.get("[key]").unwrap(), "[1, 2]");
+    }
+
+    #[test]
+    fn test_merged_groups() {
+        let pattern = Regex::new(r"^(?P<service>[^.]+)\.(?P<env>[^.]+)\.(?P<key>.+)$").unwrap();
+        let kv_config = KVConfig::from_reader(
+            "auth.prod.db_host = db\nauth.prod.db.port = 5432\nweb.prod.port = 80\n\
+             auth.dev.db_host = localhost\n_flags.auth.prod.db_host = 7\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            kv_config.merged_groups(&pattern).unwrap(),
+            vec![
+                (String::from("auth"), String::from("prod")),
+                (String::from("web"), String::from("prod")),
+                (String::from("auth"), String::from("dev")),
+            ]
+        );
+        let group = kv_config.merged_group(&pattern, "auth", "prod").unwrap();
+        assert_eq!(group.ordered_keys(), ["db_host", "db.port"]);
+        assert_eq!(group.get("db.port").unwrap(), "5432");
+        assert_eq!(group.flags("db_host"), Some(7));
+
+        let kv_config =
+            KVConfig::from_reader("auth.prod.host = a\nport = 80\n".as_bytes()).unwrap();
+        assert_eq!(
+            kv_config.merged_groups(&pattern).err().unwrap().to_string(),
+            format!(
+                "error in KV config: key port does not match merged key pattern {}",
+                pattern
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let pattern = Regex::new(r"^[a-z0-9._/]+$").unwrap();
+        let kv_config =
+            KVConfig::from_reader("db/host = a\nlog.level = info\n".as_bytes()).unwrap();
+        assert!(kv_config.check_keys(&pattern).is_ok());
+
+        let kv_config =
+            KVConfig::from_reader("db/host = a\nDb Port = 1\nnäme = b\n".as_bytes()).unwrap();
+        assert_eq!(
+            kv_config.check_keys(&pattern).err().unwrap().to_string(),
+            format!(
+                "error in KV config: key Db Port does not match key pattern {}",
+                pattern
+            )
+        );
+    }
+
+    #[test]
+    fn test_rename_keys() {
+        let mut kv_config =
+            KVConfig::from_reader("db/host = a\ndb/port = 1\n_flags.db/port = 7\n".as_bytes())
+                .unwrap();
+        kv_config
+            .rename_keys(|key| Ok(format!("v2/{}", key)))
+            .unwrap();
+        assert_eq!(kv_config.get("v2/db/host").unwrap(), "a");
+        assert_eq!(kv_config.flags("v2/db/port"), Some(7));
+        assert_eq!(kv_config.ordered_keys(), ["v2/db/host", "v2/db/port"]);
+        assert!(kv_config
+            .rename_keys(|key| Err(Error::ConfigFormat(key.to_string())))
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_dotenv() {
+        let dotenv = |line: &str| KVConfig::handle_dotenv_line(line, "=");
+        assert_eq!(
+            dotenv("export FOO=\"a b\"").unwrap(),
+            ("FOO".to_string(), "a b".to_string())
+        );
+        assert_eq!(
+            dotenv("FOO='a#b'").unwrap(),
+            ("FOO".to_string(), "a#b".to_string())
+        );
+        assert_eq!(
+            dotenv(r#"FOO="say \"hi\" \\ \$HOME" # comment"#).unwrap(),
+            ("FOO".to_string(), r#"say "hi" \ $HOME"#.to_string())
+        );
+        assert_eq!(
+            dotenv(r"FOO='no \n escapes'").unwrap(),
+            ("FOO".to_string(), r"no \n escapes".to_string())
+        );
+        assert_eq!(
+            dotenv("FOO=plain # comment").unwrap(),
+            ("FOO".to_string(), "plain".to_string())
+        );
+        assert!(dotenv("FOO=\"open").is_err());
+        assert!(dotenv("FOO=\"a\" b").is_err());
+        assert!(dotenv("FOO=''").is_err());
+
+        let kv_config = KVConfig::from_reader_with_syntax(
+            "# database\n\nexport DB_HOST=db\nDB_USER='app'\n".as_bytes(),
+            LineSyntax {
+                delimiter: "=",
+                format: LineFormat::Dotenv,
+            },
+        )
+        .unwrap();
+        assert_eq!(kv_config.get("DB_HOST").unwrap(), "db");
+        assert_eq!(kv_config.get("DB_USER").unwrap(), "app");
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(
+            KVConfig::handle_line("url: http://host:8500", ":").unwrap(),
+            ("url".to_string(), "http://host:8500".to_string())
+        );
+        assert!(KVConfig::handle_line("foo=bar", ":").is_err());
+        let kv_config = KVConfig::from_reader_with_syntax(
+            "a => x=1\nb=>2 => 3\n".as_bytes(),
+            LineSyntax {
+                delimiter: "=>",
+                format: LineFormat::Conf,
+            },
+        )
+        .unwrap();
+        assert_eq!(kv_config.get("a").unwrap(), "x=1");
+        assert_eq!(kv_config.get("b").unwrap(), "2 => 3");
+    }
+
+    #[test]
+    fn test_parse_invalid_utf8() {
+        let content: &[u8] = b"foo = bar\nbaz = \xff\xfe\n";
+        assert!(matches!(
+            KVConfig::from_reader(content),
+            Err(Error::ConfigFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let err = KVConfig::from_reader("foo = bar\nqux = 1\nbaz\n".as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "error in KV config: line 3: No delimiter found"
+        );
+
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_location_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file_path = root.join("auth.prod.conf");
+        std::fs::write(&file_path, "foo = bar\nbaz = \n").unwrap();
+        let result = KVConfig::new(&file_path);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            format!("error in KV config: {}:2: Empty value", file_path.display())
+        );
+    }
+
+    #[test]
+    fn test_parse_crlf_bom() {
+        let kv_config =
+            KVConfig::from_reader("\u{feff}first = 1\r\nsecond = two\r\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["first", "second"]);
+        assert_eq!(kv_config.get("first").unwrap(), "1");
+        assert_eq!(kv_config.get("second").unwrap(), "two");
+    }
+
+    #[test]
+    fn test_parse_continuation() {
+        let kv_config = KVConfig::from_reader(
+            "hosts = a.example.com,\\\n    b.example.com,\\\n    c.example.com\nport = 80\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["hosts", "port"]);
+        assert_eq!(
+            kv_config.get("hosts").unwrap(),
+            "a.example.com,b.example.com,c.example.com"
+        );
+
+        // An escaped trailing backslash is a literal one and ends the line
+        let kv_config = KVConfig::from_reader("root = C:\\\\\nport = 80\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.get("root").unwrap(), "C:\\");
+        assert_eq!(kv_config.get("port").unwrap(), "80");
+
+        // Errors point at the first line of a continued line
+        let err = KVConfig::from_reader("foo = bar\nbaz\\\n qux\n".as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "error in KV config: line 2: No delimiter found"
+        );
+    }
+
+    #[test]
+    fn test_file_settings() {
+        let kv_config = KVConfig::from_reader(
+            "_service = billing\n_env = prod\n_template = kv/{service}/{env}/{key}\n\
+             _prune = true\n_json.a = true\na = {}\n_owner = ops\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            kv_config.settings(),
+            &FileSettings {
+                template: Some(String::from("kv/{service}/{env}/{key}")),
+                service: Some(String::from("billing")),
+                env: Some(String::from("prod")),
+                prune: Some(true),
+                unknown: vec![String::from("_owner")],
+            }
+        );
+        assert_eq!(kv_config.ordered_keys(), &["a"]);
+
+        // Settings of a merged file win
+        let mut kv_config =
+            KVConfig::from_reader("_env = dev\n_prune = true\n".as_bytes()).unwrap();
+        kv_config.merge(KVConfig::from_reader("_env = prod\n".as_bytes()).unwrap());
+        assert_eq!(kv_config.settings().env.as_deref(), Some("prod"));
+        assert_eq!(kv_config.settings().prune, Some(true));
+
+        assert!(KVConfig::from_reader("_prune = yes\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_read_settings() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_settings_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("app.conf");
+        // Value files and includes are not read for settings only
+        std::fs::write(
+            &path,
+            "_service = billing\n#include missing.inc\ncert = @missing.pem\n_env = prod\n",
+        )
+        .unwrap();
+        let settings = KVConfig::read_settings(&path, LineSyntax::default());
+        let loaded = KVConfig::new(&path);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let settings = settings.unwrap();
+        assert_eq!(settings.service.as_deref(), Some("billing"));
+        assert_eq!(settings.env.as_deref(), Some("prod"));
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn test_parse_comment_styles() {
+        let kv_config =
+            KVConfig::from_reader("; this = that\nfoo = bar\n  ; indented\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["foo"]);
+
+        let kv_config =
+            KVConfig::from_reader("// this = that\nfoo = bar\n\t// indented\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["foo"]);
+
+        let kv_config = KVConfig::from_reader("#this = that\nfoo = bar\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["foo"]);
+
+        let kv_config =
+            KVConfig::from_reader("# plain note\nfoo = bar\n  # indented note\n".as_bytes())
+                .unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["foo"]);
+
+        // Comments ending with a backslash do not swallow the next line
+        let kv_config = KVConfig::from_reader(
+            "; see C:\\\nfoo = bar\n// dir\\\n#a = b\\\nbaz = qux\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["foo", "baz"]);
+
+        // Comment markers inside values are kept
+        let kv_config = KVConfig::from_reader("url = http://host;a\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.get("url").unwrap(), "http://host;a");
+    }
+
+    #[test]
+    fn test_parse_inline_comment() {
+        assert_eq!(
+            KVConfig::handle_line("foo=bar # c", "=").unwrap(),
+            ("foo".to_string(), "bar".to_string())
+        );
+        assert_eq!(
+            KVConfig::handle_line("foo=\"a#b\"", "=").unwrap(),
+            ("foo".to_string(), "\"a#b\"".to_string())
+        );
+        assert_eq!(
+            KVConfig::handle_line("foo=a\\#b", "=").unwrap(),
+            ("foo".to_string(), "a#b".to_string())
+        );
+        assert_eq!(
+            KVConfig::handle_line(r##"foo={"color": "#fff"} # theme"##, "=").unwrap(),
+            ("foo".to_string(), r##"{"color": "#fff"}"##.to_string())
+        );
+        assert!(KVConfig::handle_line("foo= # nothing", "=").is_err());
+    }
+
+    #[test]
+    fn test_parse_references() {
+        let mut kv_config =
+            KVConfig::from_reader("foo = bar\ndb_url @ref= config/shared/prod/db_url\n".as_bytes())
+                .unwrap();
+        assert_eq!(
+            kv_config.get("db_url").unwrap(),
+            "config/shared/prod/db_url"
+        );
+        kv_config
+            .resolve_references(|reference| {
+                assert_eq!(reference, "config/shared/prod/db_url");
+                Ok("postgres://db".to_string())
+            })
+            .unwrap();
+        assert_eq!(kv_config.get("foo").unwrap(), "bar");
+        assert_eq!(kv_config.get("db_url").unwrap(), "postgres://db");
+        assert!(KVConfig::from_reader("db_url = a\ndb_url @ref= b\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_ordered_keys() {
+        let kv_config =
+            KVConfig::from_reader("zeta = 1\n_skip = 2\nalpha = 3\nmid = 4\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["zeta", "alpha", "mid"]);
+    }
+
+    #[test]
+    fn test_parse_no_compare() {
+        let kv_config = KVConfig::from_reader(
+            "a = 1\nb = 2\n_nocompare.a = true\n_nocompare.b = false\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(kv_config.len(), 2);
+        assert!(kv_config.no_compare_keys().contains("a"));
+        assert!(!kv_config.no_compare_keys().contains("b"));
+        assert!(KVConfig::from_reader("a = 1\n_nocompare.a = yes\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let kv_config = KVConfig::from_reader("a = 1\nb = 2\n_flags.a = 42\n".as_bytes()).unwrap();
+        assert_eq!(kv_config.len(), 2);
+        assert_eq!(kv_config.flags("a"), Some(42));
+        assert_eq!(kv_config.flags("b"), None);
+        assert!(KVConfig::from_reader("a = 1\n_flags.a = -1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_file_values() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_values_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("certs")).unwrap();
+        std::fs::write(root.join("certs/server.pem"), "CERT\n").unwrap();
+        std::fs::write(
+            root.join("svc.prod.conf"),
+            "cert = @certs/server.pem\nhandle = @@team\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("broken.prod.conf"), "cert = @missing.pem\n").unwrap();
+        let kv_config = KVConfig::new(&root.join("svc.prod.conf"));
+        let broken = KVConfig::new(&root.join("broken.prod.conf"));
+        std::fs::remove_dir_all(&root).unwrap();
+        let kv_config = kv_config.unwrap();
+        assert_eq!(kv_config.get("cert").unwrap(), "CERT\n");
+        assert_eq!(kv_config.get("handle").unwrap(), "@team");
+        assert!(matches!(broken, Err(Error::ConfigFile(_))));
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |name: &str| match name {
+            "DB_PASSWORD" => Some(String::from("secret")),
+            _ => None,
+        };
+        let mut kv_config = KVConfig::from_reader(
+            "a = ${DB_PASSWORD}\nb = pre-${UNSET:-none}-post\nc = $$HOME and $5\nd = ${DB_PASSWORD:-x}\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        kv_config.expand_env(lookup).unwrap();
+        assert_eq!(kv_config.get("a").unwrap(), "secret");
+        assert_eq!(kv_config.get("b").unwrap(), "pre-none-post");
+        assert_eq!(kv_config.get("c").unwrap(), "$HOME and $5");
+        assert_eq!(kv_config.get("d").unwrap(), "secret");
+
+        let mut kv_config = KVConfig::from_reader("a = ${UNSET}\n".as_bytes()).unwrap();
+        assert!(kv_config.expand_env(lookup).is_err());
+        let mut kv_config = KVConfig::from_reader("a = ${UNSET\n".as_bytes()).unwrap();
+        assert!(kv_config.expand_env(lookup).is_err());
+    }
+
+    #[test]
+    fn test_includes() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_include_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("shared/nested")).unwrap();
+        std::fs::write(
+            root.join("svc.prod.conf"),
+            "a = 1\n#include shared/db.inc\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("shared/db.inc"),
+            "db = host\n#include nested/pool.inc\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("shared/nested/pool.inc"), "pool = 5\n").unwrap();
+        std::fs::write(
+            root.join("dup.prod.conf"),
+            "pool = 1\n#include shared/nested/pool.inc\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("x.inc"), "x = 1\n#include y.inc\n").unwrap();
+        std::fs::write(root.join("y.inc"), "y = 1\n#include x.inc\n").unwrap();
+        let kv_config = KVConfig::new(&root.join("svc.prod.conf"));
+        let duplicate = KVConfig::new(&root.join("dup.prod.conf"));
+        let cycle = KVConfig::new(&root.join("x.inc"));
+        std::fs::remove_dir_all(&root).unwrap();
+        let kv_config = kv_config.unwrap();
+        assert_eq!(kv_config.ordered_keys(), &["a", "db", "pool"]);
+        assert!(matches!(duplicate, Err(Error::DuplicateKey(_))));
+        assert!(matches!(cycle, Err(Error::ConfigFormat(_))));
+    }
+
+    #[test]
+    fn test_json_values() {
+        // Values are checked once loaded from files and expanded
+        let mut kv_config =
+            KVConfig::from_reader("a = {\"x\": 1\n_json.a = true\n".as_bytes()).unwrap();
+        assert!(kv_config.check_json_values(None, false).is_err());
+        let mut kv_config = KVConfig::from_reader("a = {\"x\": 1\n".as_bytes()).unwrap();
+        assert!(kv_config.check_json_values(None, false).is_ok());
+        let mut kv_config = KVConfig::from_reader("a = ${A}\n_json.a = true\n".as_bytes()).unwrap();
+        kv_config.expand_env(|_| Some(String::from("[1]"))).unwrap();
+        assert!(kv_config.check_json_values(None, false).is_ok());
+
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_json_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("svc.prod.conf"),
+            "policy = @policy.json\n_json.policy = true\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("policy.json"), "{\"allow\": true}").unwrap();
+        let kv_config = KVConfig::new(&root.join("svc.prod.conf"));
+        std::fs::remove_dir_all(&root).unwrap();
+        let mut kv_config = kv_config.unwrap();
+        assert!(kv_config.check_json_values(None, false).is_ok());
+
+        let mut kv_config = KVConfig::from_reader(
+            "a = {\"y\": [1, 2], \"x\": 1}\nb_json = [ true ]\nc = text\n_json.a = true\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let pattern = Regex::new("_json$").unwrap();
+        kv_config.check_json_values(Some(&pattern), true).unwrap();
+        assert_eq!(kv_config.get("a").unwrap(), r#"{"x":1,"y":[1,2]}"#);
+        assert_eq!(kv_config.get("b_json").unwrap(), "[true]");
+        assert_eq!(kv_config.get("c").unwrap(), "text");
+
+        let pattern = Regex::new("^c$").unwrap();
+        assert!(kv_config.check_json_values(Some(&pattern), false).is_err());
+    }
+
+    #[test]
+    fn test_parse_nested_formats() {
+        let kv_config =
+            KVConfig::from_json(r#"{"db": {"host": "localhost", "port": 5432}, "debug": true}"#)
+                .unwrap();
+        assert_eq!(kv_config.get("db.host").unwrap(), "localhost");
+        assert_eq!(kv_config.get("db.port").unwrap(), "5432");
+        assert_eq!(kv_config.get("debug").unwrap(), "true");
+        assert!(KVConfig::from_json(r#"{"hosts": ["a", "b"]}"#).is_err());
+        assert!(KVConfig::from_json(r#"{"a": null}"#).is_err());
+        assert!(KVConfig::from_json("[1]").is_err());
+
+        let kv_config =
+            KVConfig::from_yaml("db:\n  host: localhost\n  port: 5432\nname: ' svc '\n").unwrap();
+        assert_eq!(kv_config.get("db.host").unwrap(), "localhost");
+        assert_eq!(kv_config.get("db.port").unwrap(), "5432");
+        assert_eq!(kv_config.get("name").unwrap(), "svc");
+        assert!(KVConfig::from_yaml("hosts:\n  - a\n  - b\n").is_err());
+
+        let kv_config = KVConfig::from_toml(
+            "name = \"svc\"\ndebug = false\n\n[db]\nhost = \"localhost\"\nport = 5432\n\
+             timeout = 1.5\n\n[db.pool]\nsize = 10\n\n[log]\nlevel = 'info'\n\
+             rotated = 1979-05-27T07:32:00Z\n",
+        )
+        .unwrap();
+        let mut keys: Vec<&String> = kv_config.keys().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "db.host",
+                "db.pool.size",
+                "db.port",
+                "db.timeout",
+                "debug",
+                "log.level",
+                "log.rotated",
+                "name"
+            ]
+        );
+        assert_eq!(kv_config.get("db.port").unwrap(), "5432");
+        assert_eq!(kv_config.get("db.timeout").unwrap(), "1.5");
+        assert_eq!(kv_config.get("debug").unwrap(), "false");
+        assert_eq!(
+            kv_config.get("log.rotated").unwrap(),
+            "1979-05-27T07:32:00Z"
+        );
+        assert_eq!(
+            KVConfig::from_toml("hosts = [\"a\", \"b\"]\n")
+                .err()
+                .unwrap()
+                .to_string(),
+            "error in KV config: arrays are not supported, found in key hosts"
+        );
+        assert!(KVConfig::from_toml("[[servers]]\nhost = \"a\"\n").is_err());
+        assert!(KVConfig::from_toml("port = \n").is_err());
+
+        assert_eq!(
+            KVConfig::config_extension(Path::new("s.e.yaml.local")),
+            Some("yaml")
+        );
+        assert_eq!(
+            KVConfig::config_extension(Path::new("s.e.conf")),
+            Some("conf")
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut kv_config =
+            KVConfig::from_reader("a = 1\nb @ref= config/b\nc = 3\n".as_bytes()).unwrap();
+        let local = KVConfig::from_reader("b = 20\nd = 4\na = 10\n".as_bytes()).unwrap();
+        kv_config.merge(local);
+        assert_eq!(kv_config.get("a").unwrap(), "10");
+        assert_eq!(kv_config.get("b").unwrap(), "20");
+        assert_eq!(kv_config.get("c").unwrap(), "3");
+        assert_eq!(kv_config.get("d").unwrap(), "4");
+        assert_eq!(kv_config.ordered_keys(), &["a", "b", "c", "d"]);
+        kv_config
+            .resolve_references(|_| panic!("overridden reference must not be resolved"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let hash = KVConfig::from_reader("a = 1\nb = 2\n".as_bytes())
+            .unwrap()
+            .content_hash();
+        assert_eq!(hash.len(), 16);
+        assert_eq!(
+            hash,
+            KVConfig::from_reader("b = 2\na = 1\n".as_bytes())
+                .unwrap()
+                .content_hash()
+        );
+        assert_ne!(
+            hash,
+            KVConfig::from_reader("a = 1\nb = 3\n".as_bytes())
+                .unwrap()
+                .content_hash()
+        );
+        assert_ne!(
+            hash,
+            KVConfig::from_reader("a = 1=b\n".as_bytes())
+                .unwrap()
+                .content_hash()
+        );
+    }
+
+    #[test]
+    fn test_directives_hash() {
+        let hash = |content: &str| {
+            KVConfig::from_reader(content.as_bytes())
+                .unwrap()
+                .directives_hash()
+        };
+        let plain = hash("a = {}\n");
+        assert_eq!(plain, hash("a = {}\n"));
+        // Directives change the hash but not the content hash
+        assert_ne!(plain, hash("a = {}\n_json.a = true\n"));
+        assert_ne!(plain, hash("a = {}\n_nocompare.a = true\n"));
+        assert_ne!(hash("a = 1\n_flags.a = 1\n"), hash("a = 1\n_flags.a = 2\n"));
+        assert_eq!(
+            KVConfig::from_reader("a = 1\n_flags.a = 1\n".as_bytes())
+                .unwrap()
+                .content_hash(),
+            KVConfig::from_reader("a = 1\n".as_bytes())
+                .unwrap()
+                .content_hash()
+        );
+    }
+
+    #[test]
+    fn test_parse_matrix() {
+        let content = "key,prod,staging\n\ndb_host,db.prod,db.staging\ndebug,,true\n";
+        let matrix = KVConfig::parse_matrix(content.as_bytes(), ',').unwrap();
+        assert_eq!(matrix.envs, vec!["prod", "staging"]);
+        let prod = KVConfig::from_matrix_column(matrix, "prod").unwrap();
+        assert_eq!(prod.get("db_host").unwrap(), "db.prod");
+        assert!(prod.get("debug").is_none());
+
+        let matrix = KVConfig::parse_matrix(content.as_bytes(), ',').unwrap();
+        let staging = KVConfig::from_matrix_column(matrix, "staging").unwrap();
+        assert_eq!(staging.get("debug").unwrap(), "true");
+
+        let matrix = KVConfig::parse_matrix("key\tprod\nfoo\tbar\n".as_bytes(), '\t').unwrap();
+        assert!(KVConfig::from_matrix_column(matrix, "qa").is_err());
+
+        // ragged row
+        assert!(KVConfig::parse_matrix("key,prod\nfoo,bar,baz\n".as_bytes(), ',').is_err());
+        // no key column
+        assert!(KVConfig::parse_matrix("name,prod\nfoo,bar\n".as_bytes(), ',').is_err());
+        // no env columns
+        assert!(KVConfig::parse_matrix("key\nfoo\n".as_bytes(), ',').is_err());
+        // duplicated keys
+        let matrix = KVConfig::parse_matrix("key,prod\nfoo,a\nfoo,b\n".as_bytes(), ',').unwrap();
+        assert!(KVConfig::from_matrix_column(matrix, "prod").is_err());
+    }
+
     #[test]
     fn test_create_key() {
         let res = ServiceConfig::new(
@@ -151,6 +1863,72 @@ mod tests {
         assert_eq!(res.unwrap(), "config/my_x_MYENV*KEY");
     }
 
+    #[test]
+    fn test_create_key_vars() {
+        let service_config = ServiceConfig::new(
+            "config/{region}/{service}/{env}/{key}".to_string(),
+            "my".to_string(),
+            "prod".to_string(),
+        );
+        // Undefined variables fail
+        assert!(matches!(
+            service_config.consul_key("KEY"),
+            Err(Error::Template(_))
+        ));
+        let service_config =
+            service_config.with_vars(HashMap::from([("region".to_string(), "eu".to_string())]));
+        assert_eq!(
+            service_config.consul_key("KEY").unwrap(),
+            "config/eu/my/prod/KEY"
+        );
+        assert_eq!(
+            service_config
+                .with_template("old/{region}/{key}".to_string())
+                .consul_key("KEY")
+                .unwrap(),
+            "old/eu/KEY"
+        );
+    }
+
+    #[test]
+    fn test_create_key_empty_segments() {
+        let consul_key = |service: &str, env: &str, key: &str| {
+            ServiceConfig::new(
+                "config/{service}/{env}/{key}/value".to_string(),
+                service.to_string(),
+                env.to_string(),
+            )
+            .consul_key(key)
+        };
+        assert!(consul_key("my", "prod", "KEY").is_ok());
+        assert!(matches!(
+            consul_key("", "prod", "KEY"),
+            Err(Error::Template(_))
+        ));
+        assert!(matches!(
+            consul_key("my", "", "KEY"),
+            Err(Error::Template(_))
+        ));
+        assert!(matches!(
+            consul_key("my", "prod", ""),
+            Err(Error::Template(_))
+        ));
+        let allowed = ServiceConfig::new(
+            "config/{service}/{env}/{key}".to_string(),
+            "my".to_string(),
+            String::new(),
+        )
+        .with_empty_segments(true);
+        assert_eq!(allowed.consul_key("KEY").unwrap(), "config/my//KEY");
+        // A prefix ends with an empty key
+        let service_config = ServiceConfig::new(
+            "config/{service}/{env}/{key}".to_string(),
+            "my".to_string(),
+            "prod".to_string(),
+        );
+        assert_eq!(service_config.consul_key("").unwrap(), "config/my/prod/");
+    }
+
     #[test]
     fn test_create_key_omit() {
         // Can omit one of templates
@@ -0,0 +1,14 @@
+//! Publishing of key-value config files to the Consul KV.
+//!
+//! The `consul_kv_config` binary is a thin command-line wrapper around [`Publisher`].
+
+pub mod config;
+pub mod error;
+pub mod kv;
+pub mod logging;
+pub mod publisher;
+
+pub use crate::config::Config;
+pub use crate::error::Error;
+pub use crate::kv::{KVConfig, ServiceConfig};
+pub use crate::publisher::Publisher;
@@ -0,0 +1,87 @@
+//! JSON log lines enriched with the service, env and key being processed by the current thread.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use log::Record;
+
+thread_local! {
+    /// Context fields of log lines written by the current thread
+    static CONTEXT: RefCell<BTreeMap<&'static str, String>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Context field set for the lifetime of the guard, the previous value is restored on drop
+pub struct ContextGuard {
+    field: &'static str,
+    previous: Option<String>,
+}
+
+/// Add a field such as `service`, `env` or `key` to log lines of the current thread
+pub fn context(field: &'static str, value: &str) -> ContextGuard {
+    let previous = CONTEXT.with(|context| context.borrow_mut().insert(field, value.to_string()));
+    ContextGuard { field, previous }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            match self.previous.take() {
+                Some(previous) => context.insert(self.field, previous),
+                None => context.remove(self.field),
+            };
+        });
+    }
+}
+
+/// Build a JSON log line with the context of the current thread
+fn json_line(timestamp: &str, level: &str, message: &str) -> String {
+    let mut fields: BTreeMap<&str, String> = CONTEXT.with(|context| context.borrow().clone());
+    fields.insert("ts", timestamp.to_string());
+    fields.insert("level", level.to_lowercase());
+    fields.insert("msg", message.to_string());
+    serde_json::to_string(&fields).unwrap_or_default()
+}
+
+/// Write a log record as a JSON line, a format callback of `env_logger`
+pub fn write_json<W: Write>(buf: &mut W, timestamp: &str, record: &Record) -> std::io::Result<()> {
+    let line = json_line(
+        timestamp,
+        record.level().as_str(),
+        &record.args().to_string(),
+    );
+    writeln!(buf, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line_context() {
+        assert_eq!(
+            json_line("t", "INFO", "started"),
+            r#"{"level":"info","msg":"started","ts":"t"}"#
+        );
+        {
+            let _service = context("service", "auth");
+            let _key = context("key", "db_url");
+            {
+                let _key = context("key", "timeout");
+                assert_eq!(
+                    json_line("t", "WARN", "put"),
+                    r#"{"key":"timeout","level":"warn","msg":"put","service":"auth","ts":"t"}"#
+                );
+            }
+            assert_eq!(
+                json_line("t", "WARN", "put"),
+                r#"{"key":"db_url","level":"warn","msg":"put","service":"auth","ts":"t"}"#
+            );
+        }
+        assert_eq!(
+            json_line("t", "INFO", "done"),
+            r#"{"level":"info","msg":"done","ts":"t"}"#
+        );
+    }
+}
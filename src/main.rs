@@ -1,29 +1,154 @@
-mod config;
-mod error;
-mod kv;
-mod publisher;
+use consul_kv_config::config::{
+    read_service_tokens, Age, CasFailPolicy, ConflictPolicy, KeyRename, LogFormat, OutputFormat,
+    ServiceToken, TemplateVar,
+};
+use consul_kv_config::kv::LineFormat;
+use consul_kv_config::{logging, Config, Error, Publisher};
 
-use crate::config::Config;
-use crate::error::Error;
-use crate::publisher::Publisher;
-
-use log::{error, info};
+use log::{error, info, LevelFilter};
+use std::collections::HashMap;
+use std::path::Path;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    /// Consul address
+    /// Consul address, or a comma-separated list of addresses to fail over between
     #[structopt(
         long = "consul-addr",
         default_value = "http://localhost:8500",
-        env = "CONSUL_HTTP_ADDR"
+        env = "CONSUL_HTTP_ADDR",
+        global = true
     )]
     consul_addr: String,
 
     /// Consul token
-    #[structopt(long = "consul-token", env = "CONSUL_HTTP_TOKEN", default_value = "")]
+    #[structopt(
+        long = "consul-token",
+        env = "CONSUL_HTTP_TOKEN",
+        default_value = "",
+        global = true
+    )]
     consul_token: String,
 
+    /// File with the Consul token, overrides --consul-token
+    #[structopt(
+        long = "consul-token-file",
+        env = "CONSUL_HTTP_TOKEN_FILE",
+        global = true
+    )]
+    consul_token_file: Option<String>,
+
+    /// Consul token of a service as `service=token`, overrides the global token for its keys
+    #[structopt(long = "service-token", number_of_values = 1, global = true)]
+    service_token: Vec<ServiceToken>,
+
+    /// File with `service=token` lines
+    #[structopt(long = "service-token-file", global = true)]
+    service_token_file: Option<String>,
+
+    /// CA certificate file in PEM format to verify Consul server certificate
+    #[structopt(long = "consul-ca-cert", env = "CONSUL_CACERT", global = true)]
+    consul_ca_cert: Option<String>,
+
+    /// Client certificate file in PEM format for mutual TLS
+    #[structopt(long = "consul-client-cert", env = "CONSUL_CLIENT_CERT", global = true)]
+    consul_client_cert: Option<String>,
+
+    /// Client private key file in PKCS#8 PEM format for mutual TLS
+    #[structopt(long = "consul-client-key", env = "CONSUL_CLIENT_KEY", global = true)]
+    consul_client_key: Option<String>,
+
+    /// Consul datacenter, the datacenter of the agent by default.
+    /// Publish accepts a comma-separated list of datacenters
+    #[structopt(long, global = true)]
+    datacenter: Option<String>,
+
+    /// Consul Enterprise namespace
+    #[structopt(long = "consul-namespace", env = "CONSUL_NAMESPACE", global = true)]
+    consul_namespace: Option<String>,
+
+    /// Consul Enterprise admin partition
+    #[structopt(long = "consul-partition", env = "CONSUL_PARTITION", global = true)]
+    consul_partition: Option<String>,
+
+    /// Consul full key template, `config/service/{service}/{env}/{key}` by default.
+    /// Overrides `_template` directives of config files
+    #[structopt(long = "key-template", global = true)]
+    key_template: Option<String>,
+
+    /// Extra key template variable as `name=value`, e.g. `region=eu` for `{region}`
+    #[structopt(long = "template-var", number_of_values = 1, global = true)]
+    template_var: Vec<TemplateVar>,
+
+    /// Allow Consul keys with empty path segments like `config//key`, e.g. rendered for an empty env
+    #[structopt(long = "allow-empty-segments", global = true)]
+    allow_empty_segments: bool,
+
+    /// Publish the only value of a config file to the key template path, which has no {key}
+    #[structopt(long = "single-value", global = true)]
+    single_value: bool,
+
+    /// Require the key template to allow parsing service, env and key back from Consul keys
+    #[structopt(long = "require-reversible", global = true)]
+    require_reversible: bool,
+
+    /// Timeout for Consul to be ready in seconds
+    #[structopt(short, long, default_value = "60", global = true)]
+    timeout: u64,
+
+    /// Timeout of each HTTP request to Consul in seconds, including connecting
+    #[structopt(long = "consul-timeout", global = true)]
+    consul_timeout: Option<u64>,
+
+    /// Number of retries of a Consul request failed with a connection error
+    #[structopt(long, default_value = "3", global = true)]
+    retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled for each next retry
+    #[structopt(long = "retry-delay", default_value = "200", global = true)]
+    retry_delay: u64,
+
+    /// Format of log lines
+    #[structopt(
+        long = "log-format",
+        default_value = "text",
+        possible_values = &["text", "json"],
+        global = true
+    )]
+    log_format: LogFormat,
+
+    /// Log debug messages, or trace messages if repeated; RUST_LOG overrides it
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// Log only warnings and errors; RUST_LOG overrides it
+    #[structopt(short, long, conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+/// Options selecting config files
+#[derive(Debug, StructOpt)]
+struct SourceOpt {
+    /// Path to config file or directory with configs, `-` to read a config from stdin
+    #[structopt(short, long, required_unless_one = &["matrix", "merged"])]
+    config_path: Option<String>,
+
+    /// Path to a CSV or TSV matrix file with keys in rows and environments in columns
+    #[structopt(long, conflicts_with = "config-path")]
+    matrix: Option<String>,
+
+    /// Path to a merged config file with keys of several services and envs like `auth.prod.db_host`
+    #[structopt(long, conflicts_with_all = &["config-path", "matrix"])]
+    merged: Option<String>,
+
+    /// Regex with `service`, `env` and `key` named groups splitting keys of a merged file
+    /// [default: `service.env.key`]
+    #[structopt(long = "merged-pattern")]
+    merged_pattern: Option<String>,
+
     /// Service name
     #[structopt(short, long)]
     service: Option<String>,
@@ -32,58 +157,698 @@ struct Opt {
     #[structopt(short, long)]
     env: Option<String>,
 
-    /// Filter by environment
+    /// Filter by environment, comma-separated names or globs like `stag*`
     #[structopt(short, long)]
     filter_env: Option<String>,
 
+    /// Filter by service, comma-separated names or globs like `auth*`
+    #[structopt(long = "filter-service")]
+    filter_service: Option<String>,
+
+    /// Match service and env filters case-insensitively
+    #[structopt(long = "ignore-case")]
+    ignore_case: bool,
+
+    /// Lowercase service and env names deduced from config file paths
+    #[structopt(long = "lowercase-names")]
+    lowercase_names: bool,
+
+    /// Regex with `service` and `env` named groups matched against config file paths
+    /// relative to the config directory [default: `service.env.conf` in any directory]
+    #[structopt(long = "filename-pattern")]
+    filename_pattern: Option<String>,
+
+    /// Take service from the directory name and env from the file name, e.g. `auth/prod.conf`
+    #[structopt(long = "service-from-dir")]
+    service_from_dir: bool,
+
+    /// Path components supplying service and env, `env/service` for `prod/auth.conf`
+    /// or `service/env` for `auth/prod.conf`
+    #[structopt(long)]
+    layout: Option<String>,
+
+    /// Merge `<config>.local` files over config files
+    #[structopt(long = "allow-local-overrides")]
+    allow_local_overrides: bool,
+
+    /// Fail if no config files are found or all of them are filtered out
+    #[structopt(long = "fail-on-empty")]
+    fail_on_empty: bool,
+
+    /// Key written on every run without comparing with Consul
+    #[structopt(long = "no-compare", number_of_values = 1)]
+    no_compare: Vec<String>,
+
+    /// Publish only keys matching the glob, can be repeated
+    #[structopt(long = "include-key", number_of_values = 1)]
+    include_key: Vec<String>,
+
+    /// Neither write nor delete keys matching the glob, can be repeated
+    #[structopt(long = "exclude-key", number_of_values = 1)]
+    exclude_key: Vec<String>,
+
+    /// Remove this path prefix from every key of config files, e.g. `app` turns `app/db/host` into `db/host`
+    #[structopt(long = "key-prefix-strip")]
+    key_prefix_strip: Option<String>,
+
+    /// Add this path prefix to every key of config files, applied after --key-prefix-strip
+    #[structopt(long = "key-prefix-add")]
+    key_prefix_add: Option<String>,
+
+    /// Publish a key under a new name as `old=new` and delete the old key from Consul, can be repeated
+    #[structopt(long = "rename-key", number_of_values = 1)]
+    rename_key: Vec<KeyRename>,
+
+    /// Regex of keys whose values must be valid JSON
+    #[structopt(long = "validate-json-values")]
+    validate_json_values: Option<String>,
+
+    /// Regex every published key must match, e.g. `^[a-z0-9._/]+$`
+    #[structopt(long = "key-regex")]
+    key_regex: Option<String>,
+
+    /// List key names in Consul first and read only values of keys in config files, for prefixes with many keys
+    #[structopt(long = "read-keys-first")]
+    read_keys_first: bool,
+
+    /// Rewrite JSON values in canonical compact form before publishing
+    #[structopt(long = "canonical-json")]
+    canonical_json: bool,
+
+    /// Keep spaces around values instead of trimming them
+    #[structopt(long = "no-trim")]
+    no_trim: bool,
+
+    /// Keep double quotes around values instead of stripping them
+    #[structopt(long = "keep-quotes")]
+    keep_quotes: bool,
+
+    /// Substitute `${VAR}` and `${VAR:-default}` in values with environment variables
+    #[structopt(long = "expand-env")]
+    expand_env: bool,
+
+    /// Delimiter between keys and values in `.conf` files [default: =]
+    #[structopt(long = "delimiter")]
+    delimiter: Option<String>,
+
+    /// Format of lines of config files, `dotenv` strips `export` and unquotes values
+    #[structopt(long, default_value = "conf", possible_values = &["conf", "dotenv"])]
+    format: LineFormat,
+
+    /// Warn about keys with empty values in Consul
+    #[structopt(long = "warn-empty-remote")]
+    warn_empty_remote: bool,
+
+    /// Warn about local values that look base64-encoded
+    #[structopt(long = "warn-double-base64")]
+    warn_double_base64: bool,
+}
+
+/// Options of publishing
+#[derive(Debug, StructOpt)]
+struct PublishOpt {
     /// Dry run mode (no writes done)
     #[structopt(short, long)]
     dryrun: bool,
 
-    /// Path to config file or directory with configs
-    #[structopt(short, long)]
-    config_path: String,
+    /// Only check that config files are valid without connecting to Consul, as the validate command
+    #[structopt(long)]
+    validate: bool,
+
+    /// Stay running and publish again whenever files under the config path change
+    #[structopt(long)]
+    watch: bool,
+
+    /// Write the list of keys in config file order to the `_order` key
+    #[structopt(long = "write-order-index")]
+    write_order_index: bool,
+
+    /// Write a hash of all key-value pairs to the `_version` key
+    #[structopt(long = "write-version")]
+    write_version: bool,
+
+    /// Write the `_managed` key listing published keys and prune only keys listed there
+    #[structopt(long = "write-manifest")]
+    write_manifest: bool,
+
+    /// Read back every written key and fail if Consul holds a different value
+    #[structopt(long = "verify")]
+    verify_writes: bool,
+
+    /// Fail before writing values larger than this many bytes [default: 524288]
+    #[structopt(long = "max-value-size")]
+    max_value_size: Option<usize>,
 
-    /// Consul full key template
+    /// Store values larger than the compression threshold gzipped and flagged as compressed
+    #[structopt(long)]
+    compress: bool,
+
+    /// Compress values larger than this many bytes with --compress [default: 65536]
+    #[structopt(long = "compress-threshold")]
+    compress_threshold: Option<usize>,
+
+    /// Write only keys missing in Consul, never overwrite or delete existing keys
+    #[structopt(long = "create-only")]
+    create_only: bool,
+
+    /// Update only keys existing in Consul, never create new keys
+    #[structopt(long = "update-only")]
+    update_only: bool,
+
+    /// Policy for values switching between JSON and scalar: warn, error or overwrite
     #[structopt(
-        long = "key-template",
-        default_value = "config/service/{service}/{env}/{key}"
+        long = "on-conflict",
+        default_value = "overwrite",
+        possible_values = &["warn", "error", "overwrite"]
     )]
-    key_template: String,
+    on_conflict: ConflictPolicy,
 
-    /// Timeout for Consul to be ready in seconds
-    #[structopt(short, long, default_value = "60")]
-    timeout: u64,
+    /// Delete Consul keys missing in config files
+    #[structopt(long)]
+    prune: bool,
+
+    /// Ask before deleting keys with --prune, deletion is declined if stdout is not a terminal
+    #[structopt(long)]
+    confirm: bool,
+
+    /// Apply all changes of a config file in a single Consul transaction
+    #[structopt(long)]
+    atomic: bool,
+
+    /// Write keys only if they were not modified since they were read (check-and-set)
+    #[structopt(long)]
+    cas: bool,
+
+    /// Policy for keys modified concurrently with --cas: skip or error
+    #[structopt(
+        long = "cas-fail",
+        default_value = "error",
+        possible_values = &["skip", "error"]
+    )]
+    cas_fail: CasFailPolicy,
+
+    /// Consul flags of written keys without a `_flags.<key>` directive
+    #[structopt(long)]
+    flags: Option<u64>,
+
+    /// Print old and new values of changed keys to stdout
+    #[structopt(long)]
+    diff: bool,
+
+    /// Format of the report printed to stdout: text or json
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    output: OutputFormat,
+
+    /// Delete removed key subtrees with a single recursive delete
+    #[structopt(long = "batch-delete")]
+    batch_delete: bool,
+
+    /// Change or remove at most this many keys per run, deferring the rest to next runs
+    #[structopt(long = "max-changes")]
+    max_changes: Option<usize>,
+
+    /// Publish up to this many config files concurrently
+    #[structopt(long, default_value = "1")]
+    jobs: usize,
+
+    /// Publish the remaining config files and datacenters when one of them fails
+    #[structopt(long = "continue-on-error")]
+    continue_on_error: bool,
+
+    /// Do not show a progress bar even when stdout is a terminal
+    #[structopt(long = "no-progress")]
+    no_progress: bool,
+
+    /// Write the time of writing each key to a `_ts.<key>` key
+    #[structopt(long = "write-timestamps")]
+    write_timestamps: bool,
+
+    /// Consul full key template to additionally write all keys to, without reconciliation
+    #[structopt(long = "shadow-template")]
+    shadow_template: Option<String>,
+
+    /// Hold a Consul session lock on each service and env while publishing it
+    #[structopt(long)]
+    lock: bool,
+
+    /// Consul key template of the lock
+    #[structopt(long = "lock-template", default_value = "config/locks/{service}/{env}")]
+    lock_template: String,
+
+    /// Seconds to wait for a lock held by another publisher
+    #[structopt(long = "lock-timeout", default_value = "0")]
+    lock_timeout: u64,
+
+    /// Skip config files unchanged since their last successful publish
+    #[structopt(long = "skip-unchanged")]
+    skip_unchanged: bool,
+
+    /// File caching fingerprints of published config files for --skip-unchanged
+    #[structopt(long = "cache-file", default_value = ".consul_kv_config.cache")]
+    cache_file: String,
+
+    /// Export the current Consul keys of every config to a timestamped directory before writing
+    #[structopt(long)]
+    backup: bool,
+
+    /// Directory of backups made with --backup
+    #[structopt(long = "backup-dir", default_value = "consul_kv_config_backups")]
+    backup_dir: String,
+
+    /// Log each written and deleted key with its Consul modify index, also in JSON output
+    #[structopt(long)]
+    audit: bool,
+
+    /// Log why each key was or was not written or deleted
+    #[structopt(long = "explain-skip")]
+    explain_skip: bool,
+}
+
+/// Options selecting a single service and environment in Consul
+#[derive(Debug, StructOpt)]
+struct TargetOpt {
+    /// Service name
+    #[structopt(short, long)]
+    service: String,
+
+    /// Environment
+    #[structopt(short, long)]
+    env: String,
+}
+
+// Parsed once, and flattened options cannot be boxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Publish config files to Consul
+    Publish {
+        #[structopt(flatten)]
+        source: SourceOpt,
+
+        #[structopt(flatten)]
+        publish: PublishOpt,
+    },
+
+    /// Print keys that would be changed by publishing
+    Diff {
+        #[structopt(flatten)]
+        source: SourceOpt,
+    },
+
+    /// Fail if Consul is not in sync with config files
+    Verify {
+        #[structopt(flatten)]
+        source: SourceOpt,
+
+        /// Print drifted keys like diff and always exit successfully
+        #[structopt(long = "report-drift-only")]
+        report_drift_only: bool,
+
+        /// Format of the drift report printed to stdout: text or json
+        #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+        output: OutputFormat,
+    },
+
+    /// Check that config files are valid without connecting to Consul
+    Validate {
+        #[structopt(flatten)]
+        source: SourceOpt,
+    },
+
+    /// Export Consul keys to a config file
+    Export {
+        #[structopt(flatten)]
+        target: TargetOpt,
+
+        /// Output file, stdout if omitted
+        #[structopt(short, long)]
+        output: Option<String>,
+
+        /// Dry run mode (print instead of writing the output file)
+        #[structopt(short, long)]
+        dryrun: bool,
+    },
+
+    /// Create a config file from Consul keys for first-time setup
+    Init {
+        #[structopt(flatten)]
+        target: TargetOpt,
+
+        /// Directory to create the `service.env.conf` file in
+        #[structopt(short, long = "config-path", default_value = ".")]
+        config_path: String,
+
+        /// Overwrite an existing config file
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// List Consul keys
+    List {
+        #[structopt(flatten)]
+        target: TargetOpt,
+    },
+
+    /// Move Consul keys from another key template to the current one
+    Move {
+        #[structopt(flatten)]
+        target: TargetOpt,
+
+        /// Consul full key template to move keys from
+        #[structopt(long = "move-from")]
+        move_from: String,
+
+        /// Delete keys under the old template after moving
+        #[structopt(long = "delete-old")]
+        delete_old: bool,
+
+        /// Dry run mode (no writes done)
+        #[structopt(short, long)]
+        dryrun: bool,
+    },
+
+    /// Delete Consul keys with `_ts.<key>` timestamps older than the given age
+    Expire {
+        #[structopt(flatten)]
+        target: TargetOpt,
+
+        /// Age of keys to delete, e.g. 30m, 12h or 7d
+        #[structopt(long = "older-than")]
+        older_than: Age,
+
+        /// Dry run mode (no writes done)
+        #[structopt(short, long)]
+        dryrun: bool,
+    },
+
+    /// Delete all Consul keys of a service and environment
+    DeletePrefix {
+        #[structopt(flatten)]
+        target: TargetOpt,
+
+        /// Dry run mode (list keys instead of deleting them)
+        #[structopt(short, long)]
+        dryrun: bool,
+
+        /// Ask for confirmation before deleting
+        #[structopt(long)]
+        confirm: bool,
+    },
+}
+
+/// Key template used unless given on the command line or in a config file
+const DEFAULT_KEY_TEMPLATE: &str = "config/service/{service}/{env}/{key}";
+
+/// Exit code of a publish dry run with pending changes
+const EXIT_PENDING_CHANGES: i32 = 2;
+
+/// Run a command requiring Consul connection.
+/// Returns the number of changes of publish, zero for other commands.
+fn run_command(publisher: &Publisher, cmd: &Command) -> Result<usize, Error> {
+    let result = match cmd {
+        Command::Publish { publish, .. } if publish.watch => publisher.watch(publish.dryrun),
+        Command::Publish { publish, .. } => return publisher.process(publish.dryrun),
+        Command::Diff { .. } => publisher.diff(),
+        Command::Verify {
+            report_drift_only, ..
+        } => publisher.verify(*report_drift_only),
+        Command::Validate { .. } => Err(Error::Argument(String::from(
+            "validate does not use Consul",
+        ))),
+        Command::Export { output, dryrun, .. } => {
+            publisher.export(output.as_deref().map(Path::new), *dryrun)
+        }
+        Command::Init {
+            config_path, force, ..
+        } => publisher.init(Path::new(config_path), *force),
+        Command::List { .. } => publisher.list(),
+        Command::Move {
+            move_from,
+            delete_old,
+            dryrun,
+            ..
+        } => publisher.move_keys(move_from, *delete_old, *dryrun),
+        Command::Expire {
+            older_than, dryrun, ..
+        } => publisher.expire(older_than.0, *dryrun),
+        Command::DeletePrefix {
+            dryrun, confirm, ..
+        } => publisher.delete_prefix(*dryrun, *confirm).map(|_| ()),
+    };
+    result.map(|_| 0)
+}
+
+/// Maximum log level selected by the verbosity flags
+fn log_level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
 }
 
 fn main() -> Result<(), Error> {
-    env_logger::Builder::from_default_env()
-        .write_style(if atty::is(atty::Stream::Stdout) {
+    let opt = Opt::from_args();
+
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(log_level(opt.verbose, opt.quiet));
+    logger.parse_default_env();
+    match opt.log_format {
+        LogFormat::Text => logger.write_style(if atty::is(atty::Stream::Stdout) {
             env_logger::WriteStyle::Auto
         } else {
             env_logger::WriteStyle::Never
-        })
-        .init();
+        }),
+        LogFormat::Json => logger.format(|buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            logging::write_json(buf, &timestamp, record)
+        }),
+    };
+    logger.init();
 
-    let opt = Opt::from_args();
-    let config = Config {
+    let mut service_tokens: HashMap<String, String> = HashMap::new();
+    if let Some(path) = &opt.service_token_file {
+        for service_token in read_service_tokens(Path::new(path))? {
+            service_tokens.insert(service_token.service, service_token.token);
+        }
+    }
+    // Tokens given on the command line override the file
+    for service_token in opt.service_token {
+        service_tokens.insert(service_token.service, service_token.token);
+    }
+    let mut config = Config {
         consul_addr: opt.consul_addr,
         consul_token: opt.consul_token,
-        config_path: opt.config_path,
-        service: opt.service,
-        env: opt.env,
-        filter_env: opt.filter_env,
-        key_template: opt.key_template,
+        consul_token_file: opt.consul_token_file,
+        consul_ca_cert: opt.consul_ca_cert,
+        consul_client_cert: opt.consul_client_cert,
+        consul_client_key: opt.consul_client_key,
+        datacenter: opt.datacenter,
+        namespace: opt.consul_namespace,
+        partition: opt.consul_partition,
+        service_tokens,
+        config_path: String::new(),
+        matrix: None,
+        merged: None,
+        merged_pattern: None,
+        allow_local_overrides: false,
+        fail_on_empty: false,
+        no_compare: Vec::new(),
+        include_keys: Vec::new(),
+        exclude_keys: Vec::new(),
+        key_prefix_strip: None,
+        key_prefix_add: None,
+        rename_keys: HashMap::new(),
+        validate_json_values: None,
+        key_regex: None,
+        read_keys_first: false,
+        canonical_json: false,
+        no_trim: false,
+        keep_quotes: false,
+        expand_env: false,
+        delimiter: None,
+        line_format: LineFormat::default(),
+        service: None,
+        env: None,
+        filter_env: None,
+        filter_service: None,
+        ignore_case: false,
+        lowercase_names: false,
+        filename_pattern: None,
+        service_from_dir: false,
+        layout: None,
+        key_template_fixed: opt.key_template.is_some(),
+        key_template: opt
+            .key_template
+            .unwrap_or_else(|| String::from(DEFAULT_KEY_TEMPLATE)),
+        template_vars: opt
+            .template_var
+            .into_iter()
+            .map(|var| (var.name, var.value))
+            .collect(),
+        allow_empty_segments: opt.allow_empty_segments,
+        single_value: opt.single_value,
+        require_reversible: opt.require_reversible,
         timeout: opt.timeout,
+        consul_timeout: opt.consul_timeout,
+        retries: opt.retries,
+        retry_delay: opt.retry_delay,
+        write_order_index: false,
+        write_version: false,
+        write_manifest: false,
+        verify_writes: false,
+        max_value_size: None,
+        compress: false,
+        compress_threshold: None,
+        create_only: false,
+        update_only: false,
+        on_conflict: ConflictPolicy::default(),
+        warn_empty_remote: false,
+        warn_double_base64: false,
+        prune: false,
+        confirm: false,
+        atomic: false,
+        cas: false,
+        cas_fail: CasFailPolicy::default(),
+        flags: None,
+        diff: false,
+        output: OutputFormat::default(),
+        batch_delete: false,
+        max_changes: None,
+        jobs: 1,
+        continue_on_error: false,
+        progress: false,
+        write_timestamps: false,
+        shadow_template: None,
+        lock_template: None,
+        lock_timeout: 0,
+        cache_file: None,
+        backup_dir: None,
+        audit: false,
+        explain_skip: false,
     };
+    if let Command::Publish { publish, .. } = &opt.cmd {
+        config.write_order_index = publish.write_order_index;
+        config.write_version = publish.write_version;
+        config.write_manifest = publish.write_manifest;
+        config.verify_writes = publish.verify_writes;
+        config.max_value_size = publish.max_value_size;
+        config.compress = publish.compress;
+        config.compress_threshold = publish.compress_threshold;
+        config.create_only = publish.create_only;
+        config.update_only = publish.update_only;
+        config.on_conflict = publish.on_conflict;
+        config.prune = publish.prune;
+        config.confirm = publish.confirm;
+        config.atomic = publish.atomic;
+        config.cas = publish.cas;
+        config.cas_fail = publish.cas_fail;
+        config.flags = publish.flags;
+        config.diff = publish.diff;
+        config.output = publish.output;
+        config.batch_delete = publish.batch_delete;
+        config.max_changes = publish.max_changes;
+        config.jobs = publish.jobs;
+        config.continue_on_error = publish.continue_on_error;
+        // A progress bar would garble confirmation questions
+        config.progress =
+            !publish.no_progress && !publish.confirm && atty::is(atty::Stream::Stdout);
+        config.write_timestamps = publish.write_timestamps;
+        config.shadow_template = publish.shadow_template.clone();
+        if publish.lock {
+            config.lock_template = Some(publish.lock_template.clone());
+        }
+        config.lock_timeout = publish.lock_timeout;
+        config.audit = publish.audit;
+        if publish.skip_unchanged {
+            config.cache_file = Some(publish.cache_file.clone());
+        }
+        if publish.backup {
+            config.backup_dir = Some(publish.backup_dir.clone());
+        }
+        config.explain_skip = publish.explain_skip;
+    }
+    if let Command::Verify { output, .. } = &opt.cmd {
+        config.output = *output;
+    }
+    match &opt.cmd {
+        Command::Publish { source, .. }
+        | Command::Diff { source }
+        | Command::Verify { source, .. }
+        | Command::Validate { source } => {
+            config.config_path = source.config_path.clone().unwrap_or_default();
+            config.matrix = source.matrix.clone();
+            config.merged = source.merged.clone();
+            config.merged_pattern = source.merged_pattern.clone();
+            config.allow_local_overrides = source.allow_local_overrides;
+            config.fail_on_empty = source.fail_on_empty;
+            config.warn_empty_remote = source.warn_empty_remote;
+            config.warn_double_base64 = source.warn_double_base64;
+            config.no_compare = source.no_compare.clone();
+            config.include_keys = source.include_key.clone();
+            config.exclude_keys = source.exclude_key.clone();
+            config.key_prefix_strip = source.key_prefix_strip.clone();
+            config.key_prefix_add = source.key_prefix_add.clone();
+            config.rename_keys = source
+                .rename_key
+                .iter()
+                .map(|rename| (rename.from.clone(), rename.to.clone()))
+                .collect();
+            config.validate_json_values = source.validate_json_values.clone();
+            config.key_regex = source.key_regex.clone();
+            config.read_keys_first = source.read_keys_first;
+            config.canonical_json = source.canonical_json;
+            config.no_trim = source.no_trim;
+            config.keep_quotes = source.keep_quotes;
+            config.expand_env = source.expand_env;
+            config.delimiter = source.delimiter.clone();
+            config.line_format = source.format;
+            config.service = source.service.clone();
+            config.env = source.env.clone();
+            config.filter_env = source.filter_env.clone();
+            config.filter_service = source.filter_service.clone();
+            config.ignore_case = source.ignore_case;
+            config.lowercase_names = source.lowercase_names;
+            config.filename_pattern = source.filename_pattern.clone();
+            config.service_from_dir = source.service_from_dir;
+            config.layout = source.layout.clone();
+        }
+        Command::Export { target, .. }
+        | Command::Init { target, .. }
+        | Command::List { target }
+        | Command::Move { target, .. }
+        | Command::Expire { target, .. }
+        | Command::DeletePrefix { target, .. } => {
+            config.service = Some(target.service.clone());
+            config.env = Some(target.env.clone());
+        }
+    }
 
-    let result: Result<(), Error> = match Publisher::new(config) {
-        Ok(publisher) => publisher.process(opt.dryrun),
-        Err(err) => Err(err),
+    let result: Result<usize, Error> = match &opt.cmd {
+        // Validation works offline and never creates a Consul client
+        Command::Validate { .. } => Publisher::validate_files(&config).map(|_| 0),
+        Command::Publish { publish, .. } if publish.validate => {
+            Publisher::validate_files(&config).map(|_| 0)
+        }
+        cmd if !matches!(cmd, Command::Publish { .. })
+            && Publisher::datacenters(&config).len() > 1 =>
+        {
+            Err(Error::Argument(String::from(
+                "only publish accepts several datacenters",
+            )))
+        }
+        cmd => Publisher::new(config).and_then(|publisher| run_command(&publisher, cmd)),
     };
+    let dryrun = matches!(&opt.cmd, Command::Publish { publish, .. } if publish.dryrun);
     match result {
-        Ok(_) => {
+        Ok(changes) => {
             info!("Done");
+            // Drift detection jobs tell pending changes from errors by the exit code
+            if dryrun && changes > 0 {
+                info!("Found {} pending changes", changes);
+                std::process::exit(EXIT_PENDING_CHANGES);
+            }
             Ok(())
         }
         Err(err) => {
@@ -1,35 +1,262 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::{BufRead, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use base64::{engine::general_purpose, Engine as _};
 use consul::kv::KV;
 use consul::Client;
 use derive_more::Add;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::{ProgressBar, ProgressStyle};
+use notify::{RecursiveMode, Watcher};
 use regex::Regex;
+use serde::Serialize;
 use std::time::Duration;
 
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 
-use crate::config::Config;
+use crate::config::{CasFailPolicy, Config, ConflictPolicy, OutputFormat};
 use crate::error::Error;
-use crate::kv::KVConfig;
 use crate::kv::ServiceConfig;
+use crate::kv::{
+    FileSettings, KVConfig, LineSyntax, CONFIG_EXTENSIONS, DEFAULT_DELIMITER, STDIN_PATH,
+};
+use crate::logging;
 
 /// Config publishing statistics
-#[derive(Default, Add)]
+#[derive(Default, Clone, Add, Serialize)]
 pub struct PublishStats {
-    count: usize,
-    changed: usize,
-    existing: usize,
-    removed: usize,
+    pub count: usize,
+    pub changed: usize,
+    pub existing: usize,
+    pub removed: usize,
+    pub deferred: usize,
+    pub skipped: usize,
+    /// Removed keys deleted even without --prune, by renames and `_prune` directives
+    #[serde(skip)]
+    pub deleted: usize,
+}
+
+/// Statistics of publishing all config files to one datacenter
+struct PublishResults {
+    total: PublishStats,
+    /// Written and deleted keys with `--audit`
+    changes: Vec<ChangeEvent>,
+    files: BTreeMap<String, PublishStats>,
+    /// Errors of failed config files with `--continue-on-error`
+    failed: BTreeMap<String, String>,
+}
+
+/// Key holding the JSON list of keys in config file order
+const ORDER_KEY: &str = "_order";
+
+/// Key holding the hash of all key-value pairs
+const VERSION_KEY: &str = "_version";
+
+/// Key holding the JSON list of keys published by the tool, the only keys it may prune
+const MANIFEST_KEY: &str = "_managed";
+
+/// Keys maintained by the tool itself, excluded from reconciliation
+const META_KEYS: &[&str] = &[ORDER_KEY, VERSION_KEY, MANIFEST_KEY];
+
+/// Keys of a service read from Consul
+struct RemoteKvs {
+    /// Decoded values
+    values: HashMap<String, String>,
+    /// Modify indexes for CAS writes
+    indexes: HashMap<String, u64>,
+    /// Create indexes
+    create_indexes: HashMap<String, u64>,
+    /// Non-zero flags
+    flags: HashMap<String, u64>,
+    /// All existing keys, also those whose values were not read
+    keys: HashSet<String>,
+}
+
+/// One key read with a raw Consul API request
+struct RemotePair {
+    /// Decoded stored value, compressed if the flags say so
+    stored: String,
+    flags: u64,
+    create_index: Option<u64>,
+    modify_index: Option<u64>,
+}
+
+/// Maximum number of operations in a Consul transaction
+const TXN_MAX_OPS: usize = 64;
+
+/// Time without file changes to wait in watch mode before publishing, editors save files in several steps
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Default pattern of keys of a merged config file, `service.env.key`
+const DEFAULT_MERGED_PATTERN: &str = r"^(?P<service>[^.]+)\.(?P<env>[^.]+)\.(?P<key>.+)$";
+
+/// Default maximum size of a Consul value in bytes
+const DEFAULT_MAX_VALUE_SIZE: usize = 512 * 1024;
+
+/// Consul flags of a value stored as base64 of its gzipped bytes, "gzip" in ASCII
+const COMPRESSED_FLAGS: u64 = 0x677a_6970;
+
+/// Default minimum size of a value in bytes to compress
+const DEFAULT_COMPRESS_THRESHOLD: usize = 64 * 1024;
+
+/// Time to live of a lock session, a publisher that died holding the lock blocks others that long
+const LOCK_SESSION_TTL: &str = "600s";
+
+/// Interval of retrying to acquire a lock held by another publisher
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prefix of keys holding the unix time a key was last written, e.g. `_ts.key`
+const TIMESTAMP_PREFIX: &str = "_ts.";
+
+/// Check whether key is maintained by the tool itself
+fn is_meta_key(key: &str) -> bool {
+    META_KEYS.contains(&key) || key.starts_with(TIMESTAMP_PREFIX)
+}
+
+/// Env name of base config files merged under config files of other envs of the service
+const BASE_ENV: &str = "base";
+
+/// Audit record of a key written or deleted by a publish
+#[derive(Clone, Serialize)]
+struct ChangeEvent {
+    service: String,
+    env: String,
+    key: String,
+    /// `write` or `delete`
+    action: &'static str,
+    /// Whether the key existed before
+    existed: bool,
+    /// Consul indexes of a written key, re-read after writing
+    create_index: Option<u64>,
+    modify_index: Option<u64>,
+}
+
+/// Config file to publish with its service and env
+pub struct ConfigSource {
+    pub path: PathBuf,
+    pub service: String,
+    pub env: String,
+    /// Read the env column of a matrix file instead of a KV config file
+    pub matrix: bool,
+    /// Read keys of the service and env from a merged file, split by the pattern
+    pub merged: Option<Regex>,
+    /// Local override file merged over the config file
+    pub local_override: Option<PathBuf>,
+    /// Base config file of the service the config file is merged over
+    pub base: Option<PathBuf>,
+}
+
+impl ConfigSource {
+    /// Name of the source in reports, matrix sources are suffixed with env
+    pub fn name(&self) -> String {
+        let path = self.path.to_str().unwrap_or("");
+        if self.matrix {
+            format!("{}:{}", path, self.env)
+        } else if self.merged.is_some() {
+            format!("{}:{}.{}", path, self.service, self.env)
+        } else {
+            path.to_string()
+        }
+    }
+
+    /// Load KV config from the source, parsing line-based files with the given syntax
+    pub fn load(&self, syntax: LineSyntax) -> Result<KVConfig, Error> {
+        if self.matrix {
+            return KVConfig::from_matrix(&self.path, &self.env);
+        }
+        if let Some(pattern) = &self.merged {
+            return KVConfig::with_syntax(&self.path, syntax)?.merged_group(
+                pattern,
+                &self.service,
+                &self.env,
+            );
+        }
+        let mut kv_config = match &self.base {
+            Some(base) => {
+                info!("Merge over base config '{}'", base.to_str().unwrap_or(""));
+                let mut kv_config = KVConfig::with_syntax(base, syntax)?;
+                kv_config.merge(KVConfig::with_syntax(&self.path, syntax)?);
+                kv_config
+            }
+            None => KVConfig::with_syntax(&self.path, syntax)?,
+        };
+        if let Some(local_override) = &self.local_override {
+            info!(
+                "Merge local overrides from '{}'",
+                local_override.to_str().unwrap_or("")
+            );
+            kv_config.merge(KVConfig::with_syntax(local_override, syntax)?);
+        }
+        Ok(kv_config)
+    }
+}
+
+/// Changes required to bring Consul in sync with one KV config file
+struct ConfigChanges {
+    service_config: ServiceConfig,
+    kv_config: KVConfig,
+    existing_kvs: HashMap<String, String>,
+    /// Modify indexes of existing keys for CAS writes
+    existing_indexes: HashMap<String, u64>,
+    /// Non-zero flags of existing keys
+    existing_flags: HashMap<String, u64>,
+    /// All keys in Consul, also those whose values were not read
+    remote_keys: HashSet<String>,
+    existing_keys: HashSet<String>,
+    changed_keys: HashSet<String>,
+    /// Keys written without comparison, not counted as changed
+    forced_keys: HashSet<String>,
+    removed_keys: HashSet<String>,
+    /// Old names of renamed keys, deleted from Consul even without prune
+    renamed_keys: HashSet<String>,
+    /// Keys left untouched by the create-only or update-only mode
+    skipped_keys: HashSet<String>,
+    /// Keys of the config file filtered out by include and exclude globs
+    filtered_keys: HashSet<String>,
+    /// Delete removed keys, with --prune or a `_prune` directive
+    prune: bool,
+}
+
+/// Consul session lock on a key, released together with its session when dropped
+struct PublishLock<'a> {
+    publisher: &'a Publisher,
+    token: String,
+    key: String,
+    session: String,
+}
+
+impl Drop for PublishLock<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.publisher.release_lock(self) {
+            warn!("Cannot release lock {}: {}", self.key, err);
+        }
+    }
 }
 
 /// Config publisher
 pub struct Publisher {
-    client: Client,
-    root_path: PathBuf,
+    /// Consul clients for each address
+    clients: Vec<Client>,
+    /// Consul clients for each address using tokens of services
+    service_clients: HashMap<String, Vec<Client>>,
+    http_client: reqwest::blocking::Client,
+    /// Consul addresses to fail over between
+    addresses: Vec<String>,
+    /// Index of the address requests are sent to
+    active_address: AtomicUsize,
+    /// Audit events of the current run
+    audit_log: Mutex<Vec<ChangeEvent>>,
+    /// Unix time of the start of the run, names the backup directory
+    started: u64,
     config: Config,
 }
 
@@ -50,49 +277,547 @@ fn is_connection_error(error: &dyn std::error::Error) -> bool {
     false
 }
 
+/// Format an error with all its sources
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut result = error.to_string();
+    let mut source = error.source();
+    while let Some(err) = source {
+        result.push_str(": ");
+        result.push_str(&err.to_string());
+        source = err.source();
+    }
+    result
+}
+
+/// Check whether a connection error is caused by a failed TLS handshake,
+/// which is not transient and must not be waited out
+fn is_tls_error(error: &dyn std::error::Error) -> bool {
+    let chain = error_chain(error).to_lowercase();
+    ["certificate", "handshake", "tls", "ssl"]
+        .iter()
+        .any(|marker| chain.contains(marker))
+}
+
+/// Call `f` until it succeeds, retrying retryable errors up to `retries` times
+/// with exponentially growing delay starting from `delay`, plus random jitter
+fn retry_with_backoff<T, E, F, R>(
+    retries: u32,
+    delay: Duration,
+    is_retryable: R,
+    f: F,
+) -> Result<T, E>
+where
+    F: Fn() -> Result<T, E>,
+    R: Fn(&E) -> bool,
+    E: std::fmt::Display,
+{
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(err) if attempt < retries && is_retryable(&err) => {
+                let backoff = delay * 2u32.pow(attempt.min(16));
+                // Jitter up to half of the delay spreads retries of concurrent runs
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|now| now.subsec_nanos())
+                    .unwrap_or(0);
+                let jitter = backoff / 2 * (nanos % 1000) / 1000;
+                attempt += 1;
+                warn!(
+                    "Consul request failed: {}, retry {} of {} in {:?}",
+                    err,
+                    attempt,
+                    retries,
+                    backoff + jitter
+                );
+                std::thread::sleep(backoff + jitter);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Apply `f` to `items` on up to `jobs` threads, returning results in the order of items.
+/// No items are started after a failure, the error of the earliest failed item is returned.
+fn parallel_map<T, R, E, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+    F: Fn(&T) -> Result<R, E> + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<R, E>>>> =
+        Mutex::new(items.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+                    let result = f(item);
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+    // Items are taken in order, so all items before the earliest failure have results
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
 impl Publisher {
     /// Creates a new publisher instance
-    pub fn new(config: Config) -> Result<Publisher, Error> {
-        let client = Self::create_consul_client(&config)?;
-        let root_path = Path::new(&config.config_path).to_path_buf();
-        let publisher = Publisher {
-            client,
-            root_path,
-            config,
+    pub fn new(mut config: Config) -> Result<Publisher, Error> {
+        Self::validate(&config)?;
+        if let Some(token_file) = &config.consul_token_file {
+            config.consul_token = Self::read_token_file(Path::new(token_file))?;
+        }
+        let addresses = Self::consul_addresses(&config);
+        if addresses.is_empty() {
+            return Err(Error::Argument(String::from("no Consul address given")));
+        }
+        let http_client = Self::create_http_client(&config)?;
+        let create_clients = |token: &str| {
+            addresses
+                .iter()
+                .map(|address| {
+                    Self::create_consul_client(&config, address, token, http_client.clone())
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        };
+        let clients = create_clients(&config.consul_token)?;
+        let mut service_clients = HashMap::new();
+        for (service, token) in &config.service_tokens {
+            service_clients.insert(service.clone(), create_clients(token)?);
+        }
+        let http_client = match http_client {
+            Some(http_client) => http_client,
+            None => consul::Config::new().map_err(Error::Consul)?.http_client,
         };
-        publisher.validate()?;
-        Ok(publisher)
+        Ok(Publisher {
+            clients,
+            service_clients,
+            http_client,
+            addresses,
+            active_address: AtomicUsize::new(0),
+            audit_log: Mutex::new(Vec::new()),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| Error::Generic)?
+                .as_secs(),
+            config,
+        })
     }
 
     /// Validate configuration
-    fn validate(&self) -> Result<(), Error> {
-        // Validate template string
-        if !self.config.key_template.ends_with("/{key}") {
+    fn validate(config: &Config) -> Result<(), Error> {
+        if config.single_value {
+            Self::validate_single_value(config)?;
+        } else {
+            Self::validate_template(&config.key_template)?;
+        }
+        if config.require_reversible {
+            Self::validate_reversible_template(&config.key_template)?;
+        }
+        Self::json_pattern(config)?;
+        Self::key_pattern(config)?;
+        Self::filename_pattern(config)?;
+        if config.merged.is_some() {
+            Self::merged_pattern(config)?;
+        }
+        if config.layout.is_some() && (config.filename_pattern.is_some() || config.service_from_dir)
+        {
+            return Err(Error::Argument(String::from(
+                "layout cannot be combined with a filename pattern or service from dir",
+            )));
+        }
+        for name in ["service", "env", "key"] {
+            if config.template_vars.contains_key(name) {
+                return Err(Error::Argument(format!(
+                    "template variable {} is reserved",
+                    name
+                )));
+            }
+        }
+        if config.create_only && config.update_only {
+            return Err(Error::Argument(String::from(
+                "create only and update only modes are mutually exclusive",
+            )));
+        }
+        if let Some(shadow_template) = &config.shadow_template {
+            Self::validate_template(shadow_template)?;
+        }
+        if let Some(lock_template) = &config.lock_template {
+            if lock_template.contains("{key}") {
+                return Err(Error::Template(format!(
+                    "lock template must not contain {{key}}: {}",
+                    lock_template
+                )));
+            }
+        }
+        if Self::line_syntax(config).delimiter.is_empty() {
+            return Err(Error::Argument(String::from("delimiter must not be empty")));
+        }
+        Ok(())
+    }
+
+    /// Syntax of line-based config files
+    fn line_syntax(config: &Config) -> LineSyntax<'_> {
+        LineSyntax {
+            delimiter: config.delimiter.as_deref().unwrap_or(DEFAULT_DELIMITER),
+            format: config.line_format,
+        }
+    }
+
+    /// Compile pattern every published key must match
+    fn key_pattern(config: &Config) -> Result<Option<Regex>, Error> {
+        config
+            .key_regex
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|err| {
+                    Error::Argument(format!("invalid key pattern {}: {}", pattern, err))
+                })
+            })
+            .transpose()
+    }
+
+    /// Compile pattern of keys with JSON values
+    fn json_pattern(config: &Config) -> Result<Option<Regex>, Error> {
+        config
+            .validate_json_values
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|err| {
+                    Error::Argument(format!("invalid JSON key pattern {}: {}", pattern, err))
+                })
+            })
+            .transpose()
+    }
+
+    /// Validate template string
+    fn validate_template(key_template: &str) -> Result<(), Error> {
+        if !key_template.ends_with("/{key}") {
             return Err(Error::Template(format!(
                 "key must be at the end of template {}",
-                self.config.key_template
+                key_template
+            )));
+        }
+        Ok(())
+    }
+
+    /// Validate single value mode, meta keys would be written over the value
+    fn validate_single_value(config: &Config) -> Result<(), Error> {
+        if config.key_template.contains("{key}") {
+            return Err(Error::Template(format!(
+                "single value template must not contain {{key}}: {}",
+                config.key_template
+            )));
+        }
+        if config.write_order_index
+            || config.write_version
+            || config.write_manifest
+            || config.write_timestamps
+            || config.shadow_template.is_some()
+        {
+            return Err(Error::Argument(String::from(
+                "single value mode cannot write meta keys or shadow copies",
             )));
         }
         Ok(())
     }
 
-    /// Create a Consul client instance
-    fn create_consul_client(config: &Config) -> Result<Client, Error> {
+    /// Validate that service, env and key can be parsed back from a full Consul key
+    fn validate_reversible_template(key_template: &str) -> Result<(), Error> {
+        for placeholder in ["{service}", "{env}", "{key}"] {
+            if key_template.matches(placeholder).count() != 1 {
+                return Err(Error::Template(format!(
+                    "{} must occur exactly once in template {}",
+                    placeholder, key_template
+                )));
+            }
+        }
+        // Placeholders must be separated by slashes, otherwise a value boundary is ambiguous
+        let re = Regex::new(r"\{[^}]*\}").map_err(|_| Error::Generic)?;
+        let mut previous_end: Option<usize> = None;
+        for placeholder in re.find_iter(key_template) {
+            if let Some(end) = previous_end {
+                if !key_template[end..placeholder.start()].contains('/') {
+                    return Err(Error::Template(format!(
+                        "placeholders must be separated by / in template {}",
+                        key_template
+                    )));
+                }
+            }
+            previous_end = Some(placeholder.end());
+        }
+        Ok(())
+    }
+
+    /// Read a Consul token from a file, ignoring surrounding whitespace
+    fn read_token_file(path: &Path) -> Result<String, Error> {
+        let token = std::fs::read_to_string(path).map_err(|err| {
+            Error::ConfigFile(std::io::Error::new(
+                err.kind(),
+                format!("cannot read token file {}: {}", path.display(), err),
+            ))
+        })?;
+        Ok(token.trim().to_string())
+    }
+
+    /// Datacenters to publish to, empty for the datacenter of the agent
+    pub fn datacenters(config: &Config) -> Vec<String> {
+        config
+            .datacenter
+            .iter()
+            .flat_map(|datacenters| datacenters.split(','))
+            .map(str::trim)
+            .filter(|datacenter| !datacenter.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Consul addresses of a comma-separated list, without trailing slashes
+    fn consul_addresses(config: &Config) -> Vec<String> {
+        config
+            .consul_addr
+            .split(',')
+            .map(|address| address.trim().trim_end_matches('/'))
+            .filter(|address| !address.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Create a Consul client instance for an address, with a custom HTTP client if given
+    fn create_consul_client(
+        config: &Config,
+        address: &str,
+        token: &str,
+        http_client: Option<reqwest::blocking::Client>,
+    ) -> Result<Client, Error> {
         let mut consul_config = consul::Config::new().map_err(Error::Consul)?;
-        consul_config.address = config.consul_addr.clone();
-        consul_config.token = if config.consul_token.is_empty() {
+        consul_config.address = address.to_string();
+        consul_config.datacenter = config.datacenter.clone();
+        consul_config.token = if token.is_empty() {
             None
         } else {
-            Some(config.consul_token.clone())
+            Some(token.to_string())
         };
+        if let Some(http_client) = http_client {
+            consul_config.http_client = http_client;
+        }
         Ok(consul::Client::new(consul_config))
     }
 
-    /// Retrieve a set of existing keys and values from Consul
+    /// Create a HTTP client with a custom CA certificate, a client identity
+    /// and Consul Enterprise namespace and partition headers, if configured
+    fn create_http_client(config: &Config) -> Result<Option<reqwest::blocking::Client>, Error> {
+        let read_pem = |path: &str| {
+            std::fs::read(path).map_err(|err| Error::Tls(format!("cannot read {}: {}", path, err)))
+        };
+        let mut builder = reqwest::blocking::Client::builder();
+        let mut customized = false;
+        if let Some(ca_cert) = &config.consul_ca_cert {
+            let certificate =
+                reqwest::Certificate::from_pem(&read_pem(ca_cert)?).map_err(|err| {
+                    Error::Tls(format!("invalid CA certificate {}: {}", ca_cert, err))
+                })?;
+            builder = builder.add_root_certificate(certificate);
+            customized = true;
+        }
+        match (&config.consul_client_cert, &config.consul_client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(
+                    &read_pem(client_cert)?,
+                    &read_pem(client_key)?,
+                )
+                .map_err(|err| {
+                    Error::Tls(format!(
+                        "invalid client certificate {} or key {}: {}",
+                        client_cert, client_key, err
+                    ))
+                })?;
+                builder = builder.identity(identity);
+                customized = true;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::Argument(String::from(
+                    "client certificate and client key must be given together",
+                )))
+            }
+        }
+        // Headers apply to requests of the Consul client, which has no options for them
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in [
+            ("X-Consul-Namespace", &config.namespace),
+            ("X-Consul-Partition", &config.partition),
+        ] {
+            if let Some(value) = value {
+                let header_value =
+                    reqwest::header::HeaderValue::from_str(value).map_err(|err| {
+                        Error::Argument(format!("invalid {} {}: {}", name, value, err))
+                    })?;
+                headers.insert(name, header_value);
+            }
+        }
+        if !headers.is_empty() {
+            builder = builder.default_headers(headers);
+            customized = true;
+        }
+        // A hung Consul fails requests with a timeout error, retried like connection errors
+        if let Some(consul_timeout) = config.consul_timeout {
+            let consul_timeout = Duration::from_secs(consul_timeout);
+            builder = builder
+                .connect_timeout(consul_timeout)
+                .timeout(consul_timeout);
+            customized = true;
+        }
+        if !customized {
+            return Ok(None);
+        }
+        let http_client = builder
+            .build()
+            .map_err(|err| Error::Tls(format!("cannot create HTTP client: {}", err)))?;
+        Ok(Some(http_client))
+    }
+
+    /// Consul client of the active address using the token of the service,
+    /// or the global token if it has none
+    fn client_for(&self, service_config: &ServiceConfig) -> &Client {
+        let clients = self
+            .service_clients
+            .get(service_config.service())
+            .unwrap_or(&self.clients);
+        &clients[self.active_address.load(Ordering::SeqCst)]
+    }
+
+    /// Consul address requests are sent to
+    fn consul_addr(&self) -> &str {
+        &self.addresses[self.active_address.load(Ordering::SeqCst)]
+    }
+
+    /// Call `f` failing over to the next Consul address on connection errors,
+    /// until it succeeds or every address has failed
+    fn with_failover<T, E, F, C>(&self, is_connection_error: C, f: F) -> Result<T, E>
+    where
+        F: Fn() -> Result<T, E>,
+        C: Fn(&E) -> bool,
+        E: std::fmt::Display,
+    {
+        let mut attempt = 1;
+        loop {
+            let active = self.active_address.load(Ordering::SeqCst);
+            match f() {
+                Err(err) if attempt < self.addresses.len() && is_connection_error(&err) => {
+                    let next = (active + 1) % self.addresses.len();
+                    // Another thread may have failed over already
+                    if self
+                        .active_address
+                        .compare_exchange(active, next, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        warn!(
+                            "Consul at {} failed: {}, failing over to {}",
+                            self.addresses[active], err, self.addresses[next]
+                        );
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Token of the service, or the global token if it has none
+    fn token_for(&self, service_config: &ServiceConfig) -> &str {
+        self.config
+            .service_tokens
+            .get(service_config.service())
+            .unwrap_or(&self.config.consul_token)
+    }
+
+    /// Call a Consul API function, failing over between addresses and retrying connection errors
+    /// according to the retry options. Connection errors left after that make Consul unreachable.
+    fn with_retry<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: Fn() -> consul::errors::Result<T>,
+    {
+        let is_retryable = |err: &consul::errors::Error| is_connection_error(err);
+        retry_with_backoff(
+            self.config.retries,
+            Duration::from_millis(self.config.retry_delay),
+            is_retryable,
+            || self.with_failover(is_retryable, &f),
+        )
+        .map_err(|err| {
+            if is_retryable(&err) {
+                error!("Cannot access Consul: {}", error_chain(&err));
+                Error::Unreachable
+            } else {
+                Error::Consul(err)
+            }
+        })
+    }
+
+    /// Send a raw HTTP request built for the active Consul address, failing over between addresses
+    /// and retrying connection and server errors like `with_retry`. Other statuses are left to the caller.
+    fn send_with_retry<F>(&self, request: F) -> Result<reqwest::blocking::Response, Error>
+    where
+        F: Fn(&str) -> reqwest::blocking::RequestBuilder,
+    {
+        let is_connection_error = |err: &reqwest::Error| err.is_connect() || err.is_timeout();
+        let is_retryable = |err: &reqwest::Error| {
+            is_connection_error(err) || err.status().is_some_and(|status| status.is_server_error())
+        };
+        retry_with_backoff(
+            self.config.retries,
+            Duration::from_millis(self.config.retry_delay),
+            is_retryable,
+            || {
+                self.with_failover(is_retryable, || {
+                    let response = request(self.consul_addr()).send()?;
+                    if response.status().is_server_error() {
+                        return response.error_for_status();
+                    }
+                    Ok(response)
+                })
+            },
+        )
+        .map_err(|err| {
+            if is_connection_error(&err) {
+                error!("Cannot access Consul: {}", error_chain(&err));
+                Error::Unreachable
+            } else {
+                Error::Http(err)
+            }
+        })
+    }
+
+    /// Retrieve a set of existing keys and decoded values from Consul
     fn read_kv_from_consul(
         &self,
         service_config: &ServiceConfig,
     ) -> Result<HashMap<String, String>, Error> {
+        Ok(self.read_remote_kvs(service_config)?.values)
+    }
+
+    /// Retrieve existing keys with decoded values, modify indexes and flags from Consul
+    fn read_remote_kvs(&self, service_config: &ServiceConfig) -> Result<RemoteKvs, Error> {
         debug!("Reading existing keyset");
         let consul_key_prefix = service_config.consul_key("")?;
         // Ensure it ends with / - because we need to produce pure keys without slashes
@@ -100,108 +825,989 @@ impl Publisher {
             return Err(Error::Template(String::from("Key prefix must end with /")));
         }
         // list() returns empty vector if no prefix matched
-        let res_keys = self
-            .client
-            .list(&consul_key_prefix, None)
-            .map_err(Error::Consul)?;
-        match res_keys
-            .0
-            .into_iter()
-            .map(|rec| {
-                (
-                    rec.Key.strip_prefix(&consul_key_prefix).map(String::from),
-                    rec.Value,
+        let res_keys = self.with_retry(|| {
+            self.client_for(service_config)
+                .list(&consul_key_prefix, None)
+        })?;
+        let mut result = HashMap::new();
+        let mut indexes = HashMap::new();
+        let mut create_indexes = HashMap::new();
+        let mut flags = HashMap::new();
+        for rec in res_keys.0 {
+            let key = rec
+                .Key
+                .strip_prefix(&consul_key_prefix)
+                .map(String::from)
+                .ok_or_else(|| Error::NotFound(rec.Key.clone()))?;
+            // Folders created by the Consul UI are not keys of any config file
+            if key.is_empty() || key.ends_with('/') {
+                debug!("Skip folder {}", rec.Key);
+                continue;
+            }
+            if let Some(index) = rec.ModifyIndex {
+                indexes.insert(key.clone(), index);
+            }
+            if let Some(index) = rec.CreateIndex {
+                create_indexes.insert(key.clone(), index);
+            }
+            let mut value = Self::decode_value(&rec.Key, &rec.Value)?;
+            match rec.Flags {
+                Some(COMPRESSED_FLAGS) => value = Self::decompress_value(&rec.Key, &value)?,
+                Some(key_flags) if key_flags != 0 => {
+                    flags.insert(key.clone(), key_flags);
+                }
+                _ => {}
+            }
+            result.insert(key, value);
+        }
+        Ok(RemoteKvs {
+            keys: result.keys().cloned().collect(),
+            values: result,
+            indexes,
+            create_indexes,
+            flags,
+        })
+    }
+
+    /// Retrieve all existing keys, but values, indexes and flags only of `wanted` keys and meta keys.
+    /// Values of other keys under a large prefix are never loaded.
+    fn read_remote_kvs_of(
+        &self,
+        service_config: &ServiceConfig,
+        wanted: &HashSet<String>,
+    ) -> Result<RemoteKvs, Error> {
+        let keys = self.list_remote_keys(service_config)?;
+        let mut read_keys: Vec<&String> = keys
+            .iter()
+            .filter(|key| wanted.contains(*key) || is_meta_key(key))
+            .collect();
+        read_keys.sort();
+        debug!(
+            "Reading {} of {} existing keys",
+            read_keys.len(),
+            keys.len()
+        );
+        let mut remote_kvs = self.read_keys(service_config, &read_keys)?;
+        remote_kvs.keys = keys;
+        Ok(remote_kvs)
+    }
+
+    /// Read the given keys one by one, missing keys are left out
+    fn read_keys(
+        &self,
+        service_config: &ServiceConfig,
+        keys: &[&String],
+    ) -> Result<RemoteKvs, Error> {
+        let mut remote_kvs = RemoteKvs {
+            values: HashMap::new(),
+            indexes: HashMap::new(),
+            create_indexes: HashMap::new(),
+            flags: HashMap::new(),
+            keys: HashSet::new(),
+        };
+        for key in keys {
+            let value = self.read_single_value(service_config, key)?;
+            remote_kvs.values.extend(value.values);
+            remote_kvs.indexes.extend(value.indexes);
+            remote_kvs.create_indexes.extend(value.create_indexes);
+            remote_kvs.flags.extend(value.flags);
+            remote_kvs.keys.extend(value.keys);
+        }
+        Ok(remote_kvs)
+    }
+
+    /// List existing keys under the prefix of a service config without their values
+    fn list_remote_keys(&self, service_config: &ServiceConfig) -> Result<HashSet<String>, Error> {
+        let consul_key_prefix = service_config.consul_key("")?;
+        if !consul_key_prefix.ends_with('/') {
+            return Err(Error::Template(String::from("Key prefix must end with /")));
+        }
+        let response = self.send_with_retry(|address| {
+            let request = self
+                .http_client
+                .get(format!("{}/v1/kv/{}?keys", address, consul_key_prefix));
+            self.request_options(request, self.token_for(service_config))
+        })?;
+        // No key matched the prefix
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(HashSet::new());
+        }
+        let text = response.error_for_status()?.text()?;
+        let names: Vec<String> = serde_json::from_str(&text)
+            .map_err(|err| Error::Consul(format!("unexpected keys response: {}", err).into()))?;
+        Ok(names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&consul_key_prefix))
+            // Folders created by the Consul UI are not keys of any config file
+            .filter(|key| !key.is_empty() && !key.ends_with('/'))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Retrieve the single value stored at the template path under the key of the config file
+    fn read_single_value(
+        &self,
+        service_config: &ServiceConfig,
+        key: &str,
+    ) -> Result<RemoteKvs, Error> {
+        let consul_key = service_config.consul_key(key)?;
+        let pair = self.read_pair(&consul_key, self.token_for(service_config))?;
+        let mut remote_kvs = RemoteKvs {
+            values: HashMap::new(),
+            indexes: HashMap::new(),
+            create_indexes: HashMap::new(),
+            flags: HashMap::new(),
+            keys: HashSet::new(),
+        };
+        if let Some(pair) = pair {
+            if let Some(index) = pair.modify_index {
+                remote_kvs.indexes.insert(key.to_string(), index);
+            }
+            if let Some(index) = pair.create_index {
+                remote_kvs.create_indexes.insert(key.to_string(), index);
+            }
+            let value = match pair.flags {
+                COMPRESSED_FLAGS => Self::decompress_value(&consul_key, &pair.stored)?,
+                flags => {
+                    if flags != 0 {
+                        remote_kvs.flags.insert(key.to_string(), flags);
+                    }
+                    pair.stored
+                }
+            };
+            remote_kvs.values.insert(key.to_string(), value);
+            remote_kvs.keys.insert(key.to_string());
+        }
+        Ok(remote_kvs)
+    }
+
+    /// Read one key with its decoded value, `None` if the key is missing.
+    /// consul-rust reports a missing key as a parse error, so the status is checked directly.
+    fn read_pair(&self, consul_key: &str, token: &str) -> Result<Option<RemotePair>, Error> {
+        let response = self.send_with_retry(|address| {
+            let request = self
+                .http_client
+                .get(format!("{}/v1/kv/{}", address, consul_key));
+            self.request_options(request, token)
+        })?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let details = response.text().unwrap_or_default();
+            return Err(Error::Consul(
+                format!(
+                    "reading {} failed with status {}: {}",
+                    consul_key,
+                    status,
+                    details.trim()
                 )
-            })
-            .map(|rec| match rec {
-                (Some(x), y) => Some((x, y)),
-                (None, _y) => None,
-            })
-            .collect::<Option<HashMap<String, String>>>()
-        {
-            Some(keys) => Ok(keys),
-            None => Err(Error::Generic),
+                .into(),
+            ));
+        }
+        let text = response.text()?;
+        let invalid = || Error::Consul(format!("unexpected response for {}", consul_key).into());
+        let pairs: serde_json::Value = serde_json::from_str(&text).map_err(|_| invalid())?;
+        let pair = pairs
+            .as_array()
+            .and_then(|pairs| pairs.first())
+            .ok_or_else(invalid)?;
+        // Empty values are returned as null
+        let stored = match pair.get("Value").and_then(|value| value.as_str()) {
+            Some(value) => Self::decode_value(consul_key, value)?,
+            None => String::new(),
+        };
+        let index = |name: &str| pair.get(name).and_then(|index| index.as_u64());
+        Ok(Some(RemotePair {
+            stored,
+            flags: index("Flags").unwrap_or(0),
+            create_index: index("CreateIndex"),
+            modify_index: index("ModifyIndex"),
+        }))
+    }
+
+    /// Key holding the value of a single value config
+    fn single_value_key(kv_config: &KVConfig) -> Result<String, Error> {
+        let keys: Vec<&String> = kv_config.keys().collect();
+        match keys.as_slice() {
+            [key] => Ok(key.to_string()),
+            _ => Err(Error::ConfigFormat(format!(
+                "single value config must hold exactly one value, found {}",
+                keys.len()
+            ))),
+        }
+    }
+
+    /// Warn about keys stored in Consul with an empty value
+    fn warn_empty_values(service_config: &ServiceConfig, existing_kvs: &HashMap<String, String>) {
+        let mut empty_keys: Vec<&String> = existing_kvs
+            .iter()
+            .filter(|(_, value)| value.is_empty())
+            .map(|(key, _)| key)
+            .collect();
+        empty_keys.sort();
+        for key in empty_keys {
+            warn!(
+                "Key {} of {} has an empty value in Consul",
+                key, service_config
+            );
+        }
+    }
+
+    /// Check whether value is itself base64 of printable text, so it may end up double-encoded
+    fn is_double_base64(value: &str) -> bool {
+        // Short values such as "true" or "abcd" are valid base64 by accident
+        if value.len() < 8 || !value.len().is_multiple_of(4) {
+            return false;
+        }
+        match general_purpose::STANDARD.decode(value) {
+            Ok(decoded) => match String::from_utf8(decoded) {
+                Ok(text) => text
+                    .chars()
+                    .all(|c| !c.is_control() || c.is_ascii_whitespace()),
+                Err(_) => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Warn about local values that look base64-encoded
+    fn warn_double_base64(service_config: &ServiceConfig, kv_config: &KVConfig) {
+        let mut keys: Vec<&String> = kv_config
+            .iter()
+            .filter(|(_, value)| Self::is_double_base64(value))
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        for key in keys {
+            warn!(
+                "Key {} of {} looks base64-encoded and may be double-encoded",
+                key, service_config
+            );
         }
     }
 
-    /// Return a list of keys that was changed in local config compared to remote `existing_kvs` in Consul
+    /// Return a list of keys that was changed in local config compared to remote `existing_kvs` in Consul,
+    /// both postprocessed before comparison, or whose flags differ from `existing_flags`
     fn changed_keys(
         &self,
         service_config: &ServiceConfig,
         kv_config: &KVConfig,
+        existing_kvs: &HashMap<String, String>,
+        existing_flags: &HashMap<String, u64>,
+        forced_keys: &HashSet<String>,
     ) -> Result<HashSet<String>, Error> {
         debug!("Deduce changed keys");
         let mut result: HashSet<String> = HashSet::new();
+        let mut conflicts: Vec<String> = Vec::new();
 
-        for key in kv_config.keys() {
-            let consul_key = service_config.consul_key(key.trim_matches(' '))?;
-            let resp = self.client.get(&consul_key, None);
-            match resp {
-                Ok(kv_pair) => {
-                    // Remote value from consul
-                    let consul_raw_value = kv_pair.0.ok_or(Error::Generic)?;
-                    let decoded: Vec<u8> = general_purpose::STANDARD
-                        .decode(consul_raw_value.Value)
-                        .map_err(|_| Error::Generic)?;
-                    let consul_value_str =
-                        std::str::from_utf8(&decoded).map_err(|_| Error::Generic)?;
-                    let consul_value = self.postprocess_value(consul_value_str);
-
-                    // Local value from kv config
-                    let config_value = kv_config.get(key).ok_or(Error::Generic)?;
+        for (key, config_value) in kv_config.iter() {
+            if forced_keys.contains(key) {
+                continue;
+            }
+            match existing_kvs.get(key.trim_matches(' ')) {
+                Some(consul_value) => {
+                    let consul_value = self.postprocess_value(consul_value);
                     let existing_value = self.postprocess_value(config_value);
                     if consul_value != existing_value {
+                        if Self::is_structured(&consul_value)
+                            != Self::is_structured(&existing_value)
+                        {
+                            conflicts.push(key.clone());
+                        }
+                        result.insert(key.clone());
+                    } else if self.key_flags(kv_config, key, existing_flags)
+                        != existing_flags
+                            .get(key.trim_matches(' '))
+                            .copied()
+                            .unwrap_or(0)
+                    {
+                        debug!("Flags of key {} changed", key);
                         result.insert(key.clone());
                     }
                 }
-                Err(_) => {
-                    // Treat 404 (consul-rust says "Failed to parse JSON response") as a missing value
+                None => {
                     result.insert(key.clone());
                 }
             };
         }
 
+        self.handle_conflicts(service_config, conflicts)?;
         Ok(result)
     }
 
-    /// Put all keys from `keys` hashset from config to Consul
-    fn update_keys_in_consul(
+    /// Flags of a key: set by a directive, by the global option or kept from Consul
+    fn key_flags(
         &self,
         kv_config: &KVConfig,
+        key: &str,
+        existing_flags: &HashMap<String, u64>,
+    ) -> u64 {
+        let key = key.trim_matches(' ');
+        kv_config
+            .flags(key)
+            .or(self.config.flags)
+            .or_else(|| existing_flags.get(key).copied())
+            .unwrap_or(0)
+    }
+
+    /// Check whether value is a JSON object or array
+    fn is_structured(value: &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(value)
+            .map(|json| json.is_object() || json.is_array())
+            .unwrap_or(false)
+    }
+
+    /// Apply conflict policy to keys whose value changes shape between Consul and KV config
+    fn handle_conflicts(
+        &self,
         service_config: &ServiceConfig,
-        keys: &HashSet<String>,
+        mut conflicts: Vec<String>,
     ) -> Result<(), Error> {
-        debug!("Put keys to Consul");
-        for (key, value) in kv_config.iter() {
-            if !keys.contains(key) {
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        conflicts.sort();
+        match self.config.on_conflict {
+            ConflictPolicy::Warn => {
+                for key in &conflicts {
+                    warn!(
+                        "Key {} of {} changes shape between structured and scalar value",
+                        key, service_config
+                    );
+                }
+                Ok(())
+            }
+            ConflictPolicy::Error => Err(Error::Conflict(conflicts.join(", "))),
+            ConflictPolicy::Overwrite => {
+                debug!("Overwrite keys changing shape: {}", conflicts.join(", "));
+                Ok(())
+            }
+        }
+    }
+
+    /// Put all keys from `keys` hashset from config to Consul
+    /// With `cas_indexes` keys are written only if their modify index is unchanged
+    /// (zero for keys that did not exist), returns keys skipped by the CAS policy.
+    /// Flags of keys without configured flags are kept from `existing_flags`.
+    fn update_keys_in_consul(
+        &self,
+        kv_config: &KVConfig,
+        service_config: &ServiceConfig,
+        keys: &HashSet<String>,
+        existing_flags: &HashMap<String, u64>,
+        cas_indexes: Option<&HashMap<String, u64>>,
+    ) -> Result<HashSet<String>, Error> {
+        debug!("Put keys to Consul");
+        self.check_value_sizes(kv_config, keys)?;
+        let mut skipped = HashSet::new();
+        for (key, value) in kv_config.iter() {
+            if !keys.contains(key) {
                 debug!("Skip unchanged key {}", key);
             } else {
+                let _key = logging::context("key", key);
                 let consul_key = service_config.consul_key(key.trim_matches(' '))?;
-                let consul_val = self.postprocess_value(value);
+                let (consul_val, flags) = self.stored_value(
+                    key,
+                    self.postprocess_value(value),
+                    self.key_flags(kv_config, key, existing_flags),
+                )?;
                 debug!("Put key {}", key);
-                let kv_pair = consul::kv::KVPair {
-                    Key: consul_key,
-                    Value: consul_val,
-                    ..Default::default()
-                };
-                self.client.put_raw(&kv_pair, None).map_err(Error::Consul)?;
+                if let Some(indexes) = cas_indexes {
+                    let index = indexes.get(key).copied().unwrap_or(0);
+                    if !self.put_cas(service_config, &consul_key, &consul_val, flags, index)? {
+                        match self.config.cas_fail {
+                            CasFailPolicy::Skip => {
+                                warn!(
+                                    "Key {} of {} was modified concurrently, skipped",
+                                    key, service_config
+                                );
+                                skipped.insert(key.clone());
+                            }
+                            CasFailPolicy::Error => {
+                                return Err(Error::ConcurrentChange(key.clone()));
+                            }
+                        }
+                        continue;
+                    }
+                } else {
+                    let kv_pair = consul::kv::KVPair {
+                        Key: consul_key.clone(),
+                        Value: consul_val.clone(),
+                        Flags: Some(flags),
+                        ..Default::default()
+                    };
+                    self.with_retry(|| self.client_for(service_config).put_raw(&kv_pair, None))?;
+                }
+                if self.config.verify_writes {
+                    self.verify_write(service_config, &consul_key, &consul_val)?;
+                }
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Check that values of keys to write fit into the maximum value size,
+    /// so Consul does not reject them halfway through a config
+    fn check_value_sizes(&self, kv_config: &KVConfig, keys: &HashSet<String>) -> Result<(), Error> {
+        let max_size = self.config.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE);
+        for (key, value) in kv_config.iter() {
+            if keys.contains(key) {
+                let (stored, _) = self.stored_value(key, self.postprocess_value(value), 0)?;
+                Self::check_value_size(key, &stored, max_size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that a value is not larger than `max_size` bytes
+    fn check_value_size(key: &str, value: &str, max_size: usize) -> Result<(), Error> {
+        if value.len() > max_size {
+            return Err(Error::ConfigFormat(format!(
+                "value of key {} has {} bytes, more than the maximum of {} bytes",
+                key,
+                value.len(),
+                max_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read back a written key and check that Consul holds the written value
+    fn verify_write(
+        &self,
+        service_config: &ServiceConfig,
+        consul_key: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        let stored = self
+            .read_pair(consul_key, self.token_for(service_config))?
+            .map(|pair| pair.stored);
+        Self::check_written(consul_key, value, stored.as_deref())
+    }
+
+    /// Compare a value read back from Consul with the written one
+    fn check_written(consul_key: &str, written: &str, stored: Option<&str>) -> Result<(), Error> {
+        let mismatch = |reason: String| Err(Error::WriteMismatch(consul_key.to_string(), reason));
+        match stored {
+            None => mismatch(String::from("key is missing")),
+            Some(stored) if stored.len() != written.len() => mismatch(format!(
+                "read {} bytes instead of {}",
+                stored.len(),
+                written.len()
+            )),
+            Some(stored) if stored != written => {
+                let position = stored
+                    .bytes()
+                    .zip(written.bytes())
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(0);
+                mismatch(format!("values differ at byte {}", position))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Add the token and the datacenter to a raw Consul API request
+    fn request_options(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        token: &str,
+    ) -> reqwest::blocking::RequestBuilder {
+        let mut request = request;
+        if !token.is_empty() {
+            request = request.header("X-Consul-Token", token);
+        }
+        if let Some(datacenter) = &self.config.datacenter {
+            request = request.query(&[("dc", datacenter)]);
+        }
+        request
+    }
+
+    /// Put a value only if the key modify index equals `index`, returns false if it has moved
+    fn put_cas(
+        &self,
+        service_config: &ServiceConfig,
+        consul_key: &str,
+        value: &str,
+        flags: u64,
+        index: u64,
+    ) -> Result<bool, Error> {
+        let response = self.send_with_retry(|address| {
+            let request = self
+                .http_client
+                .put(format!("{}/v1/kv/{}", address, consul_key))
+                .query(&[("cas", index.to_string()), ("flags", flags.to_string())])
+                .body(value.to_string());
+            self.request_options(request, self.token_for(service_config))
+        })?;
+        let response = response.error_for_status()?;
+        Ok(response.text()?.trim() == "true")
+    }
+
+    /// Lock the service of `service_config` with a new session, waiting for another holder
+    /// up to the lock timeout
+    fn acquire_lock(
+        &self,
+        service_config: &ServiceConfig,
+        lock_template: &str,
+    ) -> Result<PublishLock<'_>, Error> {
+        let key = service_config
+            .with_template(lock_template.to_string())
+            .consul_key("")?;
+        let token = self.token_for(service_config).to_string();
+        let session = self.create_session(&token, &key)?;
+        // Dropping the guard destroys the session if the lock is never acquired
+        let lock = PublishLock {
+            publisher: self,
+            token,
+            key,
+            session,
+        };
+        let deadline = Instant::now() + Duration::from_secs(self.config.lock_timeout);
+        while !self.put_lock(&lock, "acquire")? {
+            if Instant::now() >= deadline {
+                return Err(Error::Locked(lock.key.clone()));
+            }
+            debug!("Lock {} is held by another publisher, waiting", lock.key);
+            std::thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+        info!("Acquired lock {}", lock.key);
+        Ok(lock)
+    }
+
+    /// Release the lock and destroy its session
+    fn release_lock(&self, lock: &PublishLock) -> Result<(), Error> {
+        self.put_lock(lock, "release")?;
+        let request = self.http_client.put(format!(
+            "{}/v1/session/destroy/{}",
+            self.consul_addr(),
+            lock.session
+        ));
+        self.request_options(request, &lock.token)
+            .send()?
+            .error_for_status()?;
+        debug!("Released lock {}", lock.key);
+        Ok(())
+    }
+
+    /// Create a Consul session deleted after its TTL if the publisher dies, returns its ID
+    fn create_session(&self, token: &str, key: &str) -> Result<String, Error> {
+        let name = format!("consul_kv_config lock {}", key);
+        let request = self
+            .http_client
+            .put(format!("{}/v1/session/create", self.consul_addr()))
+            .json(&serde_json::json!({
+                "Name": name,
+                "TTL": LOCK_SESSION_TTL,
+                "LockDelay": "0s",
+            }));
+        let response = self
+            .request_options(request, token)
+            .send()?
+            .error_for_status()?;
+        let text = response.text()?;
+        serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|session| session.get("ID")?.as_str().map(String::from))
+            .ok_or_else(|| {
+                Error::Consul(format!("unexpected session response: {}", text.trim()).into())
+            })
+    }
+
+    /// Acquire or release the lock key with its session, returns false if another session holds it
+    fn put_lock(&self, lock: &PublishLock, operation: &str) -> Result<bool, Error> {
+        let request = self
+            .http_client
+            .put(format!("{}/v1/kv/{}", self.consul_addr(), lock.key))
+            .query(&[(operation, &lock.session)])
+            .body(format!("pid {}", std::process::id()));
+        let response = self
+            .request_options(request, &lock.token)
+            .send()?
+            .error_for_status()?;
+        Ok(response.text()?.trim() == "true")
+    }
+
+    /// Read current value of a Consul key referenced from KV config with the token of the service,
+    /// decompressing values stored with `--compress`. A missing key is an error.
+    fn read_reference(
+        &self,
+        service_config: &ServiceConfig,
+        reference: &str,
+    ) -> Result<String, Error> {
+        let pair = self
+            .read_pair(reference, self.token_for(service_config))?
+            .ok_or_else(|| Error::Reference(reference.to_string()))?;
+        match pair.flags {
+            COMPRESSED_FLAGS => Self::decompress_value(reference, &pair.stored),
+            _ => Ok(pair.stored),
+        }
+    }
+
+    /// Decode base64-encoded value returned by Consul
+    fn decode_value(consul_key: &str, raw_value: &str) -> Result<String, Error> {
+        let decoded: Vec<u8> = general_purpose::STANDARD
+            .decode(raw_value)
+            .map_err(|_| Error::Decode(consul_key.to_string()))?;
+        String::from_utf8(decoded).map_err(|_| Error::InvalidUtf8(consul_key.to_string()))
+    }
+
+    /// Value and flags to store in Consul, values above the threshold are compressed with `--compress`
+    fn stored_value(&self, key: &str, value: String, flags: u64) -> Result<(String, u64), Error> {
+        let threshold = self
+            .config
+            .compress_threshold
+            .unwrap_or(DEFAULT_COMPRESS_THRESHOLD);
+        if !self.config.compress || value.len() <= threshold {
+            return Ok((value, flags));
+        }
+        // Flags are the compression marker, so they cannot carry anything else
+        if flags != 0 {
+            return Err(Error::ConfigFormat(format!(
+                "key {} with flags {} cannot be compressed",
+                key, flags
+            )));
+        }
+        Ok((Self::compress_value(&value)?, COMPRESSED_FLAGS))
+    }
+
+    /// Gzip a value and encode it with base64
+    fn compress_value(value: &str) -> Result<String, Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(value.as_bytes())
+            .map_err(|err| Error::ConfigFormat(err.to_string()))?;
+        let compressed = encoder
+            .finish()
+            .map_err(|err| Error::ConfigFormat(err.to_string()))?;
+        Ok(general_purpose::STANDARD.encode(compressed))
+    }
+
+    /// Decode a value stored by `compress_value`
+    fn decompress_value(consul_key: &str, stored: &str) -> Result<String, Error> {
+        let compressed = general_purpose::STANDARD
+            .decode(stored.trim())
+            .map_err(|_| Error::Decode(consul_key.to_string()))?;
+        let mut value = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut value)
+            .map_err(|_| Error::Decompress(consul_key.to_string()))?;
+        Ok(value)
+    }
+
+    /// Write a meta key unless Consul already holds the same value, returns true if written
+    fn update_meta_key(
+        &self,
+        changes: &ConfigChanges,
+        key: &str,
+        value: String,
+    ) -> Result<bool, Error> {
+        if changes.existing_kvs.get(key) == Some(&value) {
+            debug!("Meta key {} is up to date", key);
+            return Ok(false);
+        }
+        debug!("Put meta key {}", key);
+        let kv_pair = consul::kv::KVPair {
+            Key: changes.service_config.consul_key(key)?,
+            Value: value,
+            ..Default::default()
+        };
+        self.with_retry(|| {
+            self.client_for(&changes.service_config)
+                .put_raw(&kv_pair, None)
+        })?;
+        Ok(true)
+    }
+
+    /// Build operations of a Consul transaction writing `keys_to_write` with their timestamps
+    /// and deleting removed keys if pruning
+    fn transaction_ops(
+        &self,
+        changes: &ConfigChanges,
+        keys_to_write: &HashSet<String>,
+        timestamp: Option<u64>,
+    ) -> Result<Vec<serde_json::Value>, Error> {
+        let mut keys: Vec<&String> = keys_to_write.iter().collect();
+        keys.sort();
+        let mut ops = Vec::new();
+        for key in keys {
+            let value = changes
+                .kv_config
+                .get(key)
+                .ok_or_else(|| Error::NotFound(key.to_string()))?;
+            let set = |key: &str, value: &str| -> Result<serde_json::Value, Error> {
+                Ok(serde_json::json!({"KV": {
+                    "Verb": "set",
+                    "Key": (changes.service_config.consul_key(key)?),
+                    "Value": (general_purpose::STANDARD.encode(value)),
+                }}))
+            };
+            let consul_key = changes.service_config.consul_key(key.trim_matches(' '))?;
+            let (stored, flags) = self.stored_value(
+                key,
+                self.postprocess_value(value),
+                self.key_flags(&changes.kv_config, key, &changes.existing_flags),
+            )?;
+            let consul_value = general_purpose::STANDARD.encode(stored);
+            if self.config.cas {
+                // The whole transaction is rolled back if any index has moved
+                ops.push(serde_json::json!({"KV": {
+                    "Verb": "cas",
+                    "Key": consul_key,
+                    "Value": consul_value,
+                    "Flags": flags,
+                    "Index": (changes.existing_indexes.get(key).copied().unwrap_or(0)),
+                }}));
+            } else {
+                ops.push(serde_json::json!({"KV": {
+                    "Verb": "set",
+                    "Key": consul_key,
+                    "Value": consul_value,
+                    "Flags": flags,
+                }}));
+            }
+            if let Some(timestamp) = timestamp {
+                ops.push(set(
+                    &format!("{}{}", TIMESTAMP_PREFIX, key),
+                    &timestamp.to_string(),
+                )?);
+            }
+        }
+        let mut deleted_keys: Vec<&String> = changes.renamed_keys.iter().collect();
+        if changes.prune {
+            deleted_keys.extend(&changes.removed_keys);
+        }
+        deleted_keys.sort();
+        for key in deleted_keys {
+            ops.push(serde_json::json!({"KV": {
+                "Verb": "delete",
+                "Key": (changes.service_config.consul_key(key.trim_matches(' '))?),
+            }}));
+        }
+        Ok(ops)
+    }
+
+    /// Write and delete keys of a config in a single Consul transaction, so either all changes apply or none
+    fn apply_transaction(
+        &self,
+        changes: &ConfigChanges,
+        keys_to_write: &HashSet<String>,
+    ) -> Result<(), Error> {
+        self.check_value_sizes(&changes.kv_config, keys_to_write)?;
+        let timestamp = if self.config.write_timestamps {
+            Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| Error::Generic)?
+                    .as_secs(),
+            )
+        } else {
+            None
+        };
+        let ops = self.transaction_ops(changes, keys_to_write, timestamp)?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        if ops.len() > TXN_MAX_OPS {
+            return Err(Error::Argument(format!(
+                "{} operations for {} exceed the Consul transaction limit of {}, use --max-changes",
+                ops.len(),
+                changes.service_config,
+                TXN_MAX_OPS
+            )));
+        }
+        let body =
+            serde_json::to_string(&ops).map_err(|err| Error::ConfigFormat(err.to_string()))?;
+        let mut request = self
+            .http_client
+            .put(format!("{}/v1/txn", self.consul_addr()))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        request = self.request_options(request, self.token_for(&changes.service_config));
+        let response = request.send()?;
+        if !response.status().is_success() {
+            // Consul rolls back the whole transaction and reports errors of the operations
+            let status = response.status();
+            let details = response.text().unwrap_or_default();
+            return Err(Error::Consul(
+                format!("transaction failed with status {}: {}", status, details).into(),
+            ));
+        }
+        if self.config.verify_writes {
+            let mut keys: Vec<&String> = keys_to_write.iter().collect();
+            keys.sort();
+            for key in keys {
+                let value = changes
+                    .kv_config
+                    .get(key)
+                    .ok_or_else(|| Error::NotFound(key.to_string()))?;
+                let (stored, _) = self.stored_value(
+                    key,
+                    self.postprocess_value(value),
+                    self.key_flags(&changes.kv_config, key, &changes.existing_flags),
+                )?;
+                let consul_key = changes.service_config.consul_key(key.trim_matches(' '))?;
+                self.verify_write(&changes.service_config, &consul_key, &stored)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all keys of a config under the shadow template, without reconciliation
+    fn write_shadow(&self, changes: &ConfigChanges, shadow_template: &str) -> Result<(), Error> {
+        let shadow_service_config = changes
+            .service_config
+            .with_template(shadow_template.to_string());
+        if shadow_service_config.consul_key("")? == changes.service_config.consul_key("")? {
+            return Err(Error::Argument(String::from(
+                "shadow and main templates produce the same prefix",
+            )));
+        }
+        let all_keys: HashSet<String> = changes.kv_config.keys().cloned().collect();
+        self.update_keys_in_consul(
+            &changes.kv_config,
+            &shadow_service_config,
+            &all_keys,
+            &HashMap::new(),
+            None,
+        )?;
+        info!(
+            "Wrote {} keys of {} under shadow template",
+            all_keys.len(),
+            changes.service_config
+        );
+        Ok(())
+    }
+
+    /// Write the current unix time to timestamp keys of the given keys
+    fn update_timestamps(
+        &self,
+        service_config: &ServiceConfig,
+        keys: &HashSet<String>,
+    ) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::Generic)?
+            .as_secs();
+        for key in keys {
+            let kv_pair = consul::kv::KVPair {
+                Key: service_config.consul_key(&format!("{}{}", TIMESTAMP_PREFIX, key))?,
+                Value: now.to_string(),
+                ..Default::default()
+            };
+            self.with_retry(|| self.client_for(service_config).put_raw(&kv_pair, None))?;
+        }
+        Ok(())
+    }
+
+    /// Find keys with timestamps older than `max_age` along with their timestamp keys
+    fn expired_keys(kvs: &HashMap<String, String>, now: u64, max_age: Duration) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        for (ts_key, value) in kvs {
+            let key = match ts_key.strip_prefix(TIMESTAMP_PREFIX) {
+                Some(key) => key,
+                None => continue,
+            };
+            let timestamp: u64 = match value.parse() {
+                Ok(timestamp) => timestamp,
+                Err(_) => {
+                    warn!("Skip timestamp key {} with invalid value {}", ts_key, value);
+                    continue;
+                }
+            };
+            if now.saturating_sub(timestamp) > max_age.as_secs() {
+                if kvs.contains_key(key) {
+                    result.push(key.to_string());
+                }
+                result.push(ts_key.clone());
             }
         }
+        result.sort();
+        result
+    }
+
+    /// Write the list of keys in config file order to the order index key
+    fn update_order_index(&self, changes: &ConfigChanges) -> Result<(), Error> {
+        let order = serde_json::to_string(changes.kv_config.ordered_keys())
+            .map_err(|err| Error::ConfigFormat(err.to_string()))?;
+        if self.update_meta_key(changes, ORDER_KEY, order)? {
+            info!("Updated order index key {}", ORDER_KEY);
+        }
+        Ok(())
+    }
+
+    /// Write the content hash of the config to the version key
+    fn update_version(&self, changes: &ConfigChanges) -> Result<(), Error> {
+        let version = changes.kv_config.content_hash();
+        if self.update_meta_key(changes, VERSION_KEY, version)? {
+            info!("Updated version key {}", VERSION_KEY);
+        }
+        Ok(())
+    }
+
+    /// Write the sorted list of published keys to the manifest key
+    fn update_manifest(&self, changes: &ConfigChanges) -> Result<(), Error> {
+        let mut keys: Vec<&String> = changes.kv_config.keys().collect();
+        keys.sort();
+        let manifest =
+            serde_json::to_string(&keys).map_err(|err| Error::ConfigFormat(err.to_string()))?;
+        if self.update_meta_key(changes, MANIFEST_KEY, manifest)? {
+            info!("Updated manifest key {}", MANIFEST_KEY);
+        }
         Ok(())
     }
 
+    /// Parse keys listed in a manifest, no keys are owned without a manifest
+    fn owned_keys(manifest: Option<&String>) -> Result<HashSet<String>, Error> {
+        match manifest {
+            Some(manifest) => serde_json::from_str::<Vec<String>>(manifest)
+                .map(|keys| keys.into_iter().collect())
+                .map_err(|err| {
+                    Error::Consul(format!("invalid manifest key {}: {}", MANIFEST_KEY, err).into())
+                }),
+            None => Ok(HashSet::new()),
+        }
+    }
+
     /// Postprocess value read from KV config or Consul
     fn postprocess_value(&self, value: &str) -> String {
-        value.trim_matches(' ').trim_matches('"').into()
+        Self::trim_value(value, !self.config.no_trim, !self.config.keep_quotes)
+    }
+
+    /// Strip spaces and then double quotes around value if enabled
+    fn trim_value(value: &str, trim_spaces: bool, strip_quotes: bool) -> String {
+        let value = if trim_spaces {
+            value.trim_matches(' ')
+        } else {
+            value
+        };
+        let value = if strip_quotes {
+            value.trim_matches('"')
+        } else {
+            value
+        };
+        value.into()
     }
 
     /// Remove specified keys (like in KV config, not full) from Consul
     fn remove_keys_from_consul(
         &self,
         keys: &HashSet<String>,
+        existing_keys: &HashSet<String>,
         service_config: &ServiceConfig,
     ) -> Result<(), Error> {
-        for key in keys.iter() {
+        let mut keys: HashSet<&String> = keys.iter().collect();
+        if self.config.batch_delete {
+            for prefix in Self::removable_prefixes(&keys, existing_keys) {
+                debug!("Remove keys recursively under {}", prefix);
+                self.delete_recursive(service_config, &prefix)?;
+                keys.retain(|key| !key.starts_with(&prefix));
+            }
+        }
+        for key in keys {
+            let _key = logging::context("key", key);
             let consul_key = service_config.consul_key(key.trim_matches(' '))?;
             debug!("Remove key {}", key);
             if consul_key.starts_with('/') {
@@ -209,18 +1815,64 @@ impl Publisher {
                     "Key prefix must start with /",
                 )));
             }
-            self.client
-                .delete(&consul_key, None)
-                .map_err(Error::Consul)?;
+            self.with_retry(|| self.client_for(service_config).delete(&consul_key, None))?;
         }
         Ok(())
     }
 
+    /// Find outermost sub-prefixes with at least two keys, all of them being removed
+    fn removable_prefixes(keys: &HashSet<&String>, existing_keys: &HashSet<String>) -> Vec<String> {
+        let mut candidates: Vec<&str> = keys
+            .iter()
+            .flat_map(|key| key.match_indices('/').map(|(pos, _)| &key[..=pos]))
+            .collect();
+        // Shorter prefixes go first so nested prefixes are covered by outer ones
+        candidates.sort_by_key(|prefix| (prefix.len(), *prefix));
+        candidates.dedup();
+
+        let mut result: Vec<String> = Vec::new();
+        for prefix in candidates {
+            if result
+                .iter()
+                .any(|outer| prefix.starts_with(outer.as_str()))
+            {
+                continue;
+            }
+            let mut covered = existing_keys.iter().filter(|key| key.starts_with(prefix));
+            if covered.clone().count() >= 2 && covered.all(|key| keys.contains(key)) {
+                result.push(prefix.to_string());
+            }
+        }
+        result
+    }
+
+    /// Delete all keys under a Consul prefix with a single recursive delete
+    fn delete_recursive(&self, service_config: &ServiceConfig, prefix: &str) -> Result<(), Error> {
+        let consul_prefix = service_config.consul_key(prefix)?;
+        if consul_prefix.starts_with('/') || !consul_prefix.ends_with('/') {
+            return Err(Error::Template(format!(
+                "invalid prefix for recursive delete {}",
+                consul_prefix
+            )));
+        }
+        self.send_with_retry(|address| {
+            let request = self
+                .http_client
+                .delete(format!("{}/v1/kv/{}", address, consul_prefix))
+                .query(&[("recurse", "true")]);
+            self.request_options(request, self.token_for(service_config))
+        })?
+        .error_for_status()?;
+        Ok(())
+    }
+
     /// Deduce service and env from confug filename
-    fn deduce_service_env_from_filename(filename: &String) -> Result<(String, String), Error> {
-        let re: Regex = Regex::new(r"^(?P<service>[[:alnum:]_-]+)\.(?P<env>[[:alnum:]_-]+)\.conf$")
-            .map_err(|_| Error::Generic)?;
-        re.captures(filename)
+    fn deduce_service_env_from_filename(
+        filename: &String,
+        pattern: &Regex,
+    ) -> Result<(String, String), Error> {
+        pattern
+            .captures(filename)
             .map(|cap| {
                 (
                     cap.name("service").unwrap().as_str().to_string(),
@@ -230,205 +1882,4127 @@ impl Publisher {
             .ok_or_else(|| Error::Template(format!("Cannot parse filename {filename}")))
     }
 
-    /// Read config files in a directory
-    fn enumerate_files(&self) -> Result<Vec<PathBuf>, std::io::Error> {
-        self.root_path
-            .read_dir()?
-            // drop non-conformant files
-            .filter(|res| {
-                res.as_ref()
-                    .map(|e| {
-                        // check extension to be '.conf'
-                        e.path().extension().and_then(|s| s.to_str()).unwrap_or("") == "conf"
-                    })
-                    .unwrap_or(false)
-            })
-            .map(|res| res.map(|e| e.path()))
-            .collect()
+    /// Filename pattern of a layout of path components, e.g. `env/service` for `prod/auth.conf`.
+    /// The last component is the file name without extension, directories above the layout are ignored.
+    fn layout_pattern(layout: &str) -> Result<String, Error> {
+        let components: Vec<&str> = layout.split('/').collect();
+        let mut sorted = components.clone();
+        sorted.sort_unstable();
+        if sorted != ["env", "service"] {
+            return Err(Error::Argument(format!(
+                "layout {} must consist of service and env separated by /",
+                layout
+            )));
+        }
+        let groups: Vec<String> = components
+            .iter()
+            .map(|component| format!("(?P<{}>[[:alnum:]_-]+)", component))
+            .collect();
+        Ok(format!(
+            r"^(?:.*/)?{}\.({})$",
+            groups.join("/"),
+            CONFIG_EXTENSIONS.join("|")
+        ))
     }
 
-    /// Parse service and env from config path to a tuple of (path, service, env)
-    pub fn parse_config_paths<'a>(
-        &self,
-        config_path: &'a Path,
-    ) -> Result<(&'a Path, String, String), Error> {
-        if let (Some(the_service), Some(the_env)) = (&self.config.service, &self.config.env) {
-            let (service, env) = (the_service.clone(), the_env.clone());
-            info!(
-                "Use service {} and env {} name from command line",
-                &service, &env
-            );
-            Ok((config_path, service, env))
-        } else {
-            let config_filename: String = config_path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            let (the_service, the_env) = Self::deduce_service_env_from_filename(&config_filename)?;
-            info!(
-                "Use service {} and env {} name from config filename",
-                &the_service, &the_env
-            );
-            Ok((config_path, the_service, the_env))
+    /// Compile the pattern of keys of a merged file, `service.env.key` by default
+    fn merged_pattern(config: &Config) -> Result<Regex, Error> {
+        let pattern = config
+            .merged_pattern
+            .as_deref()
+            .unwrap_or(DEFAULT_MERGED_PATTERN);
+        let re = Regex::new(pattern).map_err(|err| {
+            Error::Template(format!("invalid merged key pattern {}: {}", pattern, err))
+        })?;
+        for group in ["service", "env", "key"] {
+            if !re.capture_names().any(|name| name == Some(group)) {
+                return Err(Error::Template(format!(
+                    "merged key pattern {} must have a named group {}",
+                    pattern, group
+                )));
+            }
         }
+        Ok(re)
     }
 
-    /// Process one KV config file
-    pub fn handle_config(
-        &self,
-        config_path: &Path,
-        service: String,
-        env: String,
-        dryrun: bool,
-    ) -> Result<PublishStats, Error> {
-        let service_config = ServiceConfig::new(self.config.key_template.clone(), service, env);
-
-        info!(
-            "Processing config file '{}' with service config {}",
-            config_path.to_str().unwrap_or(""),
-            service_config,
-        );
-
-        let kv_config = KVConfig::new(config_path)?;
-        let existing_kvs = self.read_kv_from_consul(&service_config)?;
-        let changed_keys = self.changed_keys(&service_config, &kv_config)?;
-        let existing_keys: HashSet<String> = existing_kvs.keys().cloned().collect();
-        let removed_keys = kv_config.missing_keys(&existing_keys);
-
-        info!(
-            "Read {} keys from config, found {} keys in Consul, will update {}, will delete {}",
-            kv_config.iter().len(),
-            existing_keys.len(),
-            &changed_keys.len(),
-            removed_keys.len()
-        );
+    /// Compile the filename pattern, `service.env.conf` in any directory by default
+    fn filename_pattern(config: &Config) -> Result<Regex, Error> {
+        let pattern = match (&config.filename_pattern, &config.layout) {
+            (Some(pattern), _) => pattern.clone(),
+            (None, Some(layout)) => Self::layout_pattern(layout)?,
+            (None, None) => format!(
+                r"^(?:.*/)?(?P<service>[[:alnum:]_-]+)\.(?P<env>[[:alnum:]_-]+)\.({})$",
+                CONFIG_EXTENSIONS.join("|")
+            ),
+        };
+        let re = Regex::new(&pattern).map_err(|err| {
+            Error::Template(format!("invalid filename pattern {}: {}", pattern, err))
+        })?;
+        for group in ["service", "env"] {
+            if !re.capture_names().any(|name| name == Some(group)) {
+                return Err(Error::Template(format!(
+                    "filename pattern {} must have a named group {}",
+                    pattern, group
+                )));
+            }
+        }
+        Ok(re)
+    }
 
-        if !dryrun {
-            self.update_keys_in_consul(&kv_config, &service_config, &changed_keys)?;
-            info!("Updated keys in consul");
+    /// Path of a config file matched by the filename pattern: relative to the config directory
+    /// with `/` separators, or the file name of a single config file
+    fn pattern_path(config: &Config, config_path: &Path) -> String {
+        let root_path = Path::new(&config.config_path);
+        let relative_path = if root_path.is_dir() {
+            config_path.strip_prefix(root_path).ok()
+        } else {
+            None
+        };
+        match relative_path {
+            Some(relative_path) => relative_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            None => config_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
 
-            self.remove_keys_from_consul(&removed_keys, &service_config)?;
-            info!("Removed keys from consul");
+    /// Find a local override file `<config>.local` next to the config file if allowed
+    fn local_override_path(config: &Config, config_path: &Path) -> Option<PathBuf> {
+        if !config.allow_local_overrides {
+            return None;
+        }
+        let mut local_path = config_path.as_os_str().to_owned();
+        local_path.push(".local");
+        let local_path = PathBuf::from(local_path);
+        if local_path.is_file() {
+            Some(local_path)
+        } else {
+            None
         }
+    }
 
-        Ok(PublishStats {
-            count: kv_config.iter().len(),
-            existing: existing_keys.len(),
-            changed: changed_keys.len(),
-            removed: removed_keys.len(),
-        })
+    /// Find the `<service>.base.<ext>` file next to a config file,
+    /// or `base.<ext>` when the service is the directory name
+    fn base_path(config: &Config, config_path: &Path, service: &str) -> Option<PathBuf> {
+        let directory = config_path.parent()?;
+        CONFIG_EXTENSIONS
+            .iter()
+            .map(|extension| {
+                if config.service_from_dir {
+                    directory.join(format!("{}.{}", BASE_ENV, extension))
+                } else {
+                    directory.join(format!("{}.{}.{}", service, BASE_ENV, extension))
+                }
+            })
+            .find(|base_path| base_path.is_file() && base_path != config_path)
     }
 
-    /// Waits until Consul is available, but no longer than the specified duration
-    fn wait_consul(&self, max_wait: Duration) -> Result<(), Error> {
-        let deadline = Instant::now() + max_wait;
-        loop {
-            if Instant::now() > deadline {
-                return Err(Error::Unreachable);
+    /// Deduce service from the directory name and env from the file name without extension
+    fn deduce_service_env_from_dir(config_path: &Path) -> Result<(String, String), Error> {
+        let service = config_path
+            .parent()
+            .and_then(|directory| directory.file_name())
+            .and_then(|name| name.to_str());
+        let env = config_path.file_stem().and_then(|stem| stem.to_str());
+        match (service, env) {
+            (Some(service), Some(env)) if !service.is_empty() && !env.is_empty() => {
+                Ok((service.to_string(), env.to_string()))
             }
-            match self.client.list("", None) {
-                Ok(_) => {
-                    info!("Consul is alive");
-                    return Ok(());
+            _ => Err(Error::Template(format!(
+                "Cannot parse service directory and env filename of {}",
+                config_path.display()
+            ))),
+        }
+    }
+
+    /// Read config files in a directory and its subdirectories, along with errors of unreadable ones
+    fn enumerate_files(root_path: &Path) -> (Vec<PathBuf>, Vec<std::io::Error>) {
+        let mut result = Vec::new();
+        let mut errors = Vec::new();
+        // Symlinked directories are followed once, so links to parents do not loop
+        let mut visited = HashSet::new();
+        let mut directories = vec![root_path.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            // report unreadable directories but keep walking the rest of the tree
+            let entries = match directory
+                .canonicalize()
+                .and_then(|canonical| Ok((visited.insert(canonical), directory.read_dir()?)))
+            {
+                Ok((false, _)) => {
+                    debug!(
+                        "Skip visited directory '{}'",
+                        directory.to_str().unwrap_or("")
+                    );
+                    continue;
                 }
+                Ok((true, entries)) => entries,
                 Err(err) => {
-                    if is_connection_error(&err) {
-                        info!("Cannot access Consul, retrying...");
-                        std::thread::sleep(Duration::from_secs(5));
-                    } else {
-                        return Err(Error::Consul(err));
-                    }
+                    error!(
+                        "Cannot read directory '{}': {}",
+                        directory.to_str().unwrap_or(""),
+                        err
+                    );
+                    errors.push(err);
+                    continue;
                 }
             };
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(err) => {
+                        error!(
+                            "Cannot read directory '{}': {}",
+                            directory.to_str().unwrap_or(""),
+                            err
+                        );
+                        errors.push(err);
+                        continue;
+                    }
+                };
+                if path.is_dir() {
+                    directories.push(path);
+                } else if path
+                    .extension()
+                    .and_then(|s| s.to_str())
+                    // check for a config extension, so '.conf.local' overrides are skipped
+                    .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+                {
+                    result.push(path);
+                }
+            }
         }
+        (result, errors)
     }
 
-    // Entry point
-    pub fn process(&self, dryrun: bool) -> Result<(), Error> {
-        if dryrun {
-            warn!("Running in dryrun mode, no changes allowed");
+    /// Parse service and env from config path
+    pub fn parse_config_paths(
+        config: &Config,
+        config_path: PathBuf,
+    ) -> Result<Vec<ConfigSource>, Error> {
+        if config.matrix.is_some() {
+            return Self::parse_matrix_path(config, config_path);
         }
-
-        // Wait for Consul to be ready
-        self.wait_consul(Duration::from_secs(self.config.timeout))?;
-
-        // Collect config files
-        let mut config_paths: Vec<PathBuf> = if self.root_path.is_dir() {
-            self.enumerate_files().map_err(Error::ConfigFile)?
-        } else {
-            vec![self.root_path.clone()]
+        if config.merged.is_some() {
+            return Self::parse_merged_path(config, config_path);
+        }
+        if let (Some(the_service), Some(the_env)) = (&config.service, &config.env) {
+            let (service, env) = (the_service.clone(), the_env.clone());
+            info!(
+                "Use service {} and env {} name from command line",
+                &service, &env
+            );
+            let is_stdin = config_path == Path::new(STDIN_PATH);
+            Ok(vec![ConfigSource {
+                local_override: Self::local_override_path(config, &config_path)
+                    .filter(|_| !is_stdin),
+                base: Self::base_path(config, &config_path, &service).filter(|_| !is_stdin),
+                path: config_path,
+                service,
+                env,
+                matrix: false,
+                merged: None,
+            }])
+        } else if config_path == Path::new(STDIN_PATH) {
+            Err(Error::Argument(String::from(
+                "service and env must be specified to read config from stdin",
+            )))
+        } else {
+            let config_filename = Self::pattern_path(config, &config_path);
+            let deduced = if config.service_from_dir {
+                Self::deduce_service_env_from_dir(&config_path).map(|names| {
+                    let (service, env) = Self::normalize_names(config, names);
+                    (
+                        config.service.clone().unwrap_or(service),
+                        config.env.clone().unwrap_or(env),
+                    )
+                })
+            } else {
+                Self::deduce_service_env_from_filename(
+                    &config_filename,
+                    &Self::filename_pattern(config)?,
+                )
+                .map(|names| Self::normalize_names(config, names))
+            };
+            if matches!(&deduced, Ok((_, env)) if env == BASE_ENV) {
+                debug!("Skip base config '{}'", config_filename);
+                return Ok(Vec::new());
+            }
+            // Directives of the file win over deduced names, but not over the command line
+            let settings = Self::file_settings(config, &config_path);
+            let service = settings.service.filter(|_| config.service.is_none());
+            let env = settings.env.filter(|_| config.env.is_none());
+            let (the_service, the_env) = match (deduced, service, env) {
+                // Self-describing files need not follow the filename pattern
+                (_, Some(service), Some(env)) => (service, env),
+                (deduced, service, env) => {
+                    let (deduced_service, deduced_env) = deduced?;
+                    (
+                        service.unwrap_or(deduced_service),
+                        env.unwrap_or(deduced_env),
+                    )
+                }
+            };
+            info!(
+                "Use service {} and env {} name from config file",
+                &the_service, &the_env
+            );
+            Ok(vec![ConfigSource {
+                local_override: Self::local_override_path(config, &config_path),
+                base: Self::base_path(config, &config_path, &the_service),
+                path: config_path,
+                service: the_service,
+                env: the_env,
+                matrix: false,
+                merged: None,
+            }])
+        }
+    }
+
+    /// Publishing settings of a config file, default ones if it cannot be read.
+    /// Errors of the file are reported when it is loaded for publishing.
+    fn file_settings(config: &Config, config_path: &Path) -> FileSettings {
+        match KVConfig::read_settings(config_path, Self::line_syntax(config)) {
+            Ok(settings) => settings,
+            Err(err) => {
+                debug!(
+                    "No settings of config file '{}': {}",
+                    config_path.to_str().unwrap_or(""),
+                    err
+                );
+                FileSettings::default()
+            }
+        }
+    }
+
+    /// Warn about directives of a config file that are not recognized
+    fn warn_unknown_directives(source: &ConfigSource, kv_config: &KVConfig) {
+        for directive in &kv_config.settings().unknown {
+            warn!(
+                "Ignore unknown directive {} of config file '{}'",
+                directive,
+                source.path.to_str().unwrap_or("")
+            );
+        }
+    }
+
+    /// Lowercase deduced service and env names if configured
+    fn normalize_names(config: &Config, (service, env): (String, String)) -> (String, String) {
+        if config.lowercase_names {
+            (service.to_lowercase(), env.to_lowercase())
+        } else {
+            (service, env)
+        }
+    }
+
+    /// Make a config source for each service and env group of keys of a merged file
+    fn parse_merged_path(
+        config: &Config,
+        merged_path: PathBuf,
+    ) -> Result<Vec<ConfigSource>, Error> {
+        let pattern = Self::merged_pattern(config)?;
+        let groups = KVConfig::with_syntax(&merged_path, Self::line_syntax(config))?
+            .merged_groups(&pattern)?;
+        info!("Use services and envs {:?} from merged file", &groups);
+        Ok(groups
+            .into_iter()
+            .filter(|(service, env)| {
+                config.service.as_ref().is_none_or(|name| name == service)
+                    && config.env.as_ref().is_none_or(|name| name == env)
+            })
+            .map(|(service, env)| ConfigSource {
+                path: merged_path.clone(),
+                service,
+                env,
+                matrix: false,
+                merged: Some(pattern.clone()),
+                local_override: None,
+                base: None,
+            })
+            .collect())
+    }
+
+    /// Make a config source for each env column of a matrix file
+    fn parse_matrix_path(
+        config: &Config,
+        matrix_path: PathBuf,
+    ) -> Result<Vec<ConfigSource>, Error> {
+        let service = config.service.clone().ok_or_else(|| {
+            Error::Argument(String::from("service must be specified for a matrix file"))
+        })?;
+        let envs = match &config.env {
+            Some(env) => vec![env.clone()],
+            None => KVConfig::matrix_envs(&matrix_path)?,
+        };
+        info!("Use service {} and envs {:?} from matrix", &service, &envs);
+        Ok(envs
+            .into_iter()
+            .map(|env| ConfigSource {
+                path: matrix_path.clone(),
+                service: service.clone(),
+                env,
+                matrix: true,
+                merged: None,
+                local_override: None,
+                base: None,
+            })
+            .collect())
+    }
+
+    /// Load a config file with the keys and values to publish before reading Consul,
+    /// along with its service config, whether keys missing from it are pruned
+    /// and its keys filtered out by include and exclude globs
+    fn local_kv_config(
+        &self,
+        source: &ConfigSource,
+    ) -> Result<(KVConfig, ServiceConfig, bool, HashSet<String>), Error> {
+        let mut kv_config = source.load(Self::line_syntax(&self.config))?;
+        let service_config = Self::file_service_config(&self.config, source, &kv_config);
+        let prune = self.config.prune || kv_config.settings().prune == Some(true);
+        kv_config.rename_keys(|key| Self::published_key(&self.config, key))?;
+        Self::apply_renames(&self.config, &mut kv_config)?;
+        if let Some(pattern) = Self::key_pattern(&self.config)? {
+            kv_config.check_keys(&pattern)?;
+        }
+        let filtered_keys: HashSet<String> = kv_config
+            .keys()
+            .filter(|key| !Self::key_selected(&self.config, key))
+            .cloned()
+            .collect();
+        kv_config.retain_keys(|key| Self::key_selected(&self.config, key));
+        if self.config.expand_env {
+            kv_config.expand_env(|name| std::env::var(name).ok())?;
+        }
+        Ok((kv_config, service_config, prune, filtered_keys))
+    }
+
+    /// Compare one KV config file with Consul
+    fn compute_changes(&self, source: &ConfigSource) -> Result<ConfigChanges, Error> {
+        let (mut kv_config, service_config, prune, filtered_keys) = self.local_kv_config(source)?;
+        Self::warn_unknown_directives(source, &kv_config);
+
+        info!(
+            "Processing config file '{}' with service config {}",
+            source.path.to_str().unwrap_or(""),
+            service_config,
+        );
+
+        if self.config.warn_double_base64 {
+            Self::warn_double_base64(&service_config, &kv_config);
+        }
+        kv_config
+            .resolve_references(|reference| self.read_reference(&service_config, reference))?;
+        kv_config.check_json_values(
+            Self::json_pattern(&self.config)?.as_ref(),
+            self.config.canonical_json,
+        )?;
+        let RemoteKvs {
+            values: existing_kvs,
+            indexes: existing_indexes,
+            flags: existing_flags,
+            keys: remote_keys,
+            ..
+        } = if self.config.single_value {
+            self.read_single_value(&service_config, &Self::single_value_key(&kv_config)?)?
+        } else if self.config.read_keys_first {
+            let wanted: HashSet<String> = kv_config
+                .keys()
+                .map(|key| key.trim_matches(' ').to_string())
+                .chain(self.config.rename_keys.keys().cloned())
+                .collect();
+            self.read_remote_kvs_of(&service_config, &wanted)?
+        } else {
+            self.read_remote_kvs(&service_config)?
+        };
+        if self.config.warn_empty_remote {
+            Self::warn_empty_values(&service_config, &existing_kvs);
+        }
+        let mut forced_keys: HashSet<String> = kv_config
+            .keys()
+            .filter(|key| {
+                self.config.no_compare.contains(key) || kv_config.no_compare_keys().contains(*key)
+            })
+            .cloned()
+            .collect();
+        let mut changed_keys = self.changed_keys(
+            &service_config,
+            &kv_config,
+            &existing_kvs,
+            &existing_flags,
+            &forced_keys,
+        )?;
+        let mut skipped_keys = HashSet::new();
+        if self.config.create_only || self.config.update_only {
+            // Existing keys may have been tuned by hand since they were created,
+            // new keys may be typos
+            for keys in [&mut changed_keys, &mut forced_keys] {
+                keys.retain(|key| {
+                    let exists = existing_kvs.contains_key(key.trim_matches(' '));
+                    if exists == self.config.create_only {
+                        skipped_keys.insert(key.clone());
+                        return false;
+                    }
+                    true
+                });
+            }
+        }
+        if self.config.update_only && !skipped_keys.is_empty() {
+            let mut new_keys: Vec<&String> = skipped_keys.iter().collect();
+            new_keys.sort();
+            warn!(
+                "Skipped {} new keys of {} missing in Consul: {}",
+                new_keys.len(),
+                service_config,
+                new_keys
+                    .iter()
+                    .map(|key| key.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        // Keys filtered out by include and exclude globs are never removed
+        let existing_keys: HashSet<String> = remote_keys
+            .iter()
+            .filter(|key| !is_meta_key(key) && Self::key_selected(&self.config, key))
+            .cloned()
+            .collect();
+        let mut removed_keys = kv_config.missing_keys(&existing_keys);
+        if self.config.create_only {
+            removed_keys.clear();
+        }
+        if self.config.write_manifest {
+            // Keys published by other systems under the same prefix are never removed
+            let owned_keys = Self::owned_keys(existing_kvs.get(MANIFEST_KEY))?;
+            removed_keys.retain(|key| owned_keys.contains(key));
+        }
+        // Old names of renamed keys are deleted even without prune
+        let mut renamed_keys: HashSet<String> = self
+            .config
+            .rename_keys
+            .iter()
+            .filter(|(from, to)| existing_kvs.contains_key(*from) && kv_config.contains_key(*to))
+            .map(|(from, _)| from.clone())
+            .collect();
+        removed_keys.retain(|key| !renamed_keys.contains(key));
+        // Timestamps of removed keys go along with them
+        let timestamps = |keys: &HashSet<String>| -> Vec<String> {
+            keys.iter()
+                .map(|key| format!("{}{}", TIMESTAMP_PREFIX, key))
+                .filter(|ts_key| existing_kvs.contains_key(ts_key))
+                .collect()
+        };
+        let removed_timestamps = timestamps(&removed_keys);
+        let renamed_timestamps = timestamps(&renamed_keys);
+        removed_keys.extend(removed_timestamps);
+        renamed_keys.extend(renamed_timestamps);
+
+        info!(
+            "Read {} keys from config, found {} keys in Consul, will update {}, will delete {}",
+            kv_config.iter().len(),
+            existing_keys.len(),
+            &changed_keys.len(),
+            removed_keys.len() + renamed_keys.len()
+        );
+
+        Ok(ConfigChanges {
+            service_config,
+            kv_config,
+            existing_kvs,
+            existing_indexes,
+            existing_flags,
+            remote_keys,
+            existing_keys,
+            changed_keys,
+            forced_keys,
+            removed_keys,
+            renamed_keys,
+            skipped_keys,
+            filtered_keys,
+            prune,
+        })
+    }
+
+    /// Log and record an audit event for each written and deleted key of a config,
+    /// re-reading Consul indexes of written keys
+    fn record_changes(
+        &self,
+        changes: &ConfigChanges,
+        written_keys: &HashSet<String>,
+    ) -> Result<(), Error> {
+        let remote = if self.config.single_value {
+            let key = Self::single_value_key(&changes.kv_config)?;
+            self.read_single_value(&changes.service_config, &key)?
+        } else if self.config.read_keys_first {
+            // Deleted keys have no indexes left, so only written keys are read
+            let mut keys: Vec<&String> = written_keys.iter().collect();
+            keys.sort();
+            self.read_keys(&changes.service_config, &keys)?
+        } else {
+            self.read_remote_kvs(&changes.service_config)?
+        };
+        let event = |key: &String, action| {
+            let name = key.trim_matches(' ');
+            ChangeEvent {
+                service: changes.service_config.service().to_string(),
+                env: changes.service_config.env().to_string(),
+                key: name.to_string(),
+                action,
+                existed: changes.existing_keys.contains(name),
+                create_index: remote.create_indexes.get(name).copied(),
+                modify_index: remote.indexes.get(name).copied(),
+            }
+        };
+        let mut written: Vec<&String> = written_keys.iter().collect();
+        written.sort();
+        let mut events: Vec<ChangeEvent> =
+            written.into_iter().map(|key| event(key, "write")).collect();
+        if changes.prune {
+            let mut removed: Vec<&String> = changes.removed_keys.iter().collect();
+            removed.sort();
+            events.extend(removed.into_iter().map(|key| event(key, "delete")));
+        }
+        let mut renamed: Vec<&String> = changes.renamed_keys.iter().collect();
+        renamed.sort();
+        events.extend(renamed.into_iter().map(|key| event(key, "delete")));
+        for event in &events {
+            let _key = logging::context("key", &event.key);
+            match event.modify_index {
+                Some(index) => info!(
+                    "Audit: {} key {} at index {}",
+                    event.action, event.key, index
+                ),
+                None => info!("Audit: {} key {}", event.action, event.key),
+            }
+        }
+        self.audit_log.lock().unwrap().extend(events);
+        Ok(())
+    }
+
+    /// Process one KV config file
+    /// At most `max_changes` keys are changed or removed if given, the rest is deferred.
+    /// Returns the source name with its statistics.
+    pub fn handle_config(
+        &self,
+        source: &ConfigSource,
+        dryrun: bool,
+        max_changes: Option<usize>,
+    ) -> Result<(String, PublishStats), Error> {
+        let _service = logging::context("service", &source.service);
+        let _env = logging::context("env", &source.env);
+        let _lock = match &self.config.lock_template {
+            Some(lock_template) if !dryrun => {
+                let service_config =
+                    Self::service_config(&self.config, source.service.clone(), source.env.clone());
+                Some(self.acquire_lock(&service_config, lock_template)?)
+            }
+            _ => None,
+        };
+        let mut changes = self.compute_changes(source)?;
+        // Reading is safe in dryrun mode, so backups are made there as well
+        if let Some(backup_dir) = &self.config.backup_dir {
+            self.write_backup(&changes, Path::new(backup_dir))?;
+        }
+        let mut deferred_keys = match max_changes {
+            Some(limit) if changes.prune => {
+                Self::limit_changes(&mut changes.changed_keys, &mut changes.removed_keys, limit)
+            }
+            // Keys that are not deleted do not count against the limit
+            Some(limit) => {
+                Self::limit_changes(&mut changes.changed_keys, &mut HashSet::new(), limit)
+            }
+            None => HashSet::new(),
+        };
+        // An old key stays until its new key is written
+        let mut waiting_keys = HashSet::new();
+        changes.renamed_keys.retain(|key| {
+            let name = key.strip_prefix(TIMESTAMP_PREFIX).unwrap_or(key);
+            match self.config.rename_keys.get(name) {
+                Some(to) if deferred_keys.contains(to) => {
+                    waiting_keys.insert(key.clone());
+                    false
+                }
+                Some(to) => !changes.skipped_keys.contains(to),
+                None => true,
+            }
+        });
+        deferred_keys.extend(waiting_keys);
+        // Deletions of old names take what is left of the limit
+        if let Some(limit) = max_changes {
+            let removed = if changes.prune {
+                changes.removed_keys.len()
+            } else {
+                0
+            };
+            let applied = limit.saturating_sub(changes.changed_keys.len() + removed);
+            let mut renamed_keys: Vec<String> = changes.renamed_keys.drain().collect();
+            renamed_keys.sort();
+            for (pos, key) in renamed_keys.into_iter().enumerate() {
+                if pos < applied {
+                    changes.renamed_keys.insert(key);
+                } else {
+                    deferred_keys.insert(key);
+                }
+            }
+        }
+        let deferred = deferred_keys.len();
+        if self.config.diff {
+            self.print_changes(&changes)?;
+        }
+        if self.config.explain_skip {
+            self.explain_keys(&changes, &deferred_keys);
+        }
+        if deferred > 0 {
+            warn!(
+                "Deferred {} key changes of {} to next runs",
+                deferred, changes.service_config
+            );
+        }
+
+        if !dryrun
+            && changes.prune
+            && self.config.confirm
+            && !changes.removed_keys.is_empty()
+            && !self.confirm_removal(&changes)?
+        {
+            warn!(
+                "Kept {} keys of {}, deletion is not confirmed",
+                changes.removed_keys.len(),
+                changes.service_config
+            );
+            changes.removed_keys.clear();
+        }
+
+        // Nothing is written for an up-to-date config, so repeated runs have no side effects
+        let up_to_date = changes.changed_keys.is_empty()
+            && changes.forced_keys.is_empty()
+            && (changes.removed_keys.is_empty() || !changes.prune)
+            && changes.renamed_keys.is_empty();
+        if up_to_date {
+            info!("No changes for {}", changes.service_config);
+        }
+
+        if !dryrun && !up_to_date {
+            let mut keys_to_write: HashSet<String> = changes
+                .changed_keys
+                .union(&changes.forced_keys)
+                .cloned()
+                .collect();
+            if self.config.atomic {
+                self.apply_transaction(&changes, &keys_to_write)?;
+                info!("Applied changes to consul in a transaction");
+            } else {
+                let cas_indexes = if self.config.cas {
+                    Some(&changes.existing_indexes)
+                } else {
+                    None
+                };
+                let skipped_keys = self.update_keys_in_consul(
+                    &changes.kv_config,
+                    &changes.service_config,
+                    &keys_to_write,
+                    &changes.existing_flags,
+                    cas_indexes,
+                )?;
+                keys_to_write.retain(|key| !skipped_keys.contains(key));
+                info!("Updated keys in consul");
+
+                if self.config.write_timestamps {
+                    self.update_timestamps(&changes.service_config, &keys_to_write)?;
+                }
+
+                if changes.prune {
+                    self.remove_keys_from_consul(
+                        &changes.removed_keys,
+                        &changes.remote_keys,
+                        &changes.service_config,
+                    )?;
+                    info!("Removed keys from consul");
+                }
+                if !changes.renamed_keys.is_empty() {
+                    self.remove_keys_from_consul(
+                        &changes.renamed_keys,
+                        &changes.remote_keys,
+                        &changes.service_config,
+                    )?;
+                    info!("Removed old names of renamed keys from consul");
+                }
+            }
+
+            if self.config.audit {
+                self.record_changes(&changes, &keys_to_write)?;
+            }
+        }
+
+        if !dryrun {
+            // Shadow keys are never compared, so they are written on every run
+            if let Some(shadow_template) = &self.config.shadow_template {
+                self.write_shadow(&changes, shadow_template)?;
+            }
+
+            if !changes.prune && !changes.removed_keys.is_empty() {
+                warn!(
+                    "Kept {} keys of {} missing in config, use --prune to delete them",
+                    changes.removed_keys.len(),
+                    changes.service_config
+                );
+            }
+
+            // Meta keys describe the whole config, so they wait until all changes are applied.
+            // They are written only when out of date.
+            if deferred == 0 {
+                if self.config.write_order_index {
+                    self.update_order_index(&changes)?;
+                }
+                if self.config.write_version {
+                    self.update_version(&changes)?;
+                }
+                if self.config.write_manifest {
+                    self.update_manifest(&changes)?;
+                }
+            }
+        }
+
+        let stats = PublishStats {
+            count: changes.kv_config.iter().len(),
+            existing: changes.existing_keys.len(),
+            changed: changes.changed_keys.len(),
+            removed: changes.removed_keys.len() + changes.renamed_keys.len(),
+            deferred,
+            skipped: changes.skipped_keys.len(),
+            deleted: changes.renamed_keys.len()
+                + if changes.prune {
+                    changes.removed_keys.len()
+                } else {
+                    0
+                },
+        };
+        Ok((source.name(), stats))
+    }
+
+    /// Export Consul keys of a config as they were before publishing to
+    /// `<backup_dir>/<unix time>/<service>.<env>.conf`, a directory that can be published back.
+    /// Directories of datacenters get a `-<dc>` suffix.
+    fn write_backup(&self, changes: &ConfigChanges, backup_dir: &Path) -> Result<(), Error> {
+        let run = match &self.config.datacenter {
+            Some(datacenter) => format!("{}-{}", self.started, datacenter),
+            None => self.started.to_string(),
+        };
+        let directory = backup_dir.join(run);
+        std::fs::create_dir_all(&directory).map_err(Error::ConfigFile)?;
+        let path = directory.join(format!(
+            "{}.{}.conf",
+            changes.service_config.service(),
+            changes.service_config.env()
+        ));
+        let mut existing_kvs = if self.config.read_keys_first {
+            self.read_kv_from_consul(&changes.service_config)?
+        } else {
+            changes.existing_kvs.clone()
+        };
+        Self::retain_exportable(&mut existing_kvs);
+        let content = format!(
+            "// backup of {} before publishing\n{}",
+            changes.service_config,
+            Self::render_kvs(&existing_kvs, self.config.expand_env)
+        );
+        std::fs::write(&path, content).map_err(Error::ConfigFile)?;
+        info!(
+            "Backed up {} keys of {} to '{}'",
+            existing_kvs.len(),
+            changes.service_config,
+            path.to_str().unwrap_or("")
+        );
+        Ok(())
+    }
+
+    /// Keep only the first `limit` changes, changed keys sorted first, then removed keys sorted.
+    /// Returns the deferred keys.
+    fn limit_changes(
+        changed_keys: &mut HashSet<String>,
+        removed_keys: &mut HashSet<String>,
+        limit: usize,
+    ) -> HashSet<String> {
+        if changed_keys.len() + removed_keys.len() <= limit {
+            return HashSet::new();
+        }
+        let mut sorted_changed: Vec<String> = changed_keys.drain().collect();
+        sorted_changed.sort();
+        let mut sorted_removed: Vec<String> = removed_keys.drain().collect();
+        sorted_removed.sort();
+        let applied_changed = sorted_changed.len().min(limit);
+        let applied_removed = limit - applied_changed;
+        let mut deferred_keys = HashSet::new();
+        for (pos, key) in sorted_changed.into_iter().enumerate() {
+            if pos < applied_changed {
+                changed_keys.insert(key);
+            } else {
+                deferred_keys.insert(key);
+            }
+        }
+        for (pos, key) in sorted_removed.into_iter().enumerate() {
+            if pos < applied_removed {
+                removed_keys.insert(key);
+            } else {
+                deferred_keys.insert(key);
+            }
+        }
+        deferred_keys
+    }
+
+    /// Log the disposition of every local and remote key of a config
+    fn explain_keys(&self, changes: &ConfigChanges, deferred_keys: &HashSet<String>) {
+        let mut keys: Vec<&String> = changes
+            .kv_config
+            .keys()
+            .chain(&changes.remote_keys)
+            .chain(&changes.filtered_keys)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let reason = self.key_disposition(changes, deferred_keys, key);
+            info!("Key {} of {}: {}", key, changes.service_config, reason);
+        }
+    }
+
+    /// Disposition of a local or remote key of a config
+    fn key_disposition(
+        &self,
+        changes: &ConfigChanges,
+        deferred_keys: &HashSet<String>,
+        key: &str,
+    ) -> &'static str {
+        if deferred_keys.contains(key) {
+            "deferred by max changes"
+        } else if changes.skipped_keys.contains(key) {
+            if self.config.create_only {
+                "kept, exists in Consul with create only"
+            } else {
+                "skipped, missing in Consul with update only"
+            }
+        } else if changes.forced_keys.contains(key) {
+            "written without comparison"
+        } else if changes.changed_keys.contains(key) {
+            if changes.existing_kvs.contains_key(key) {
+                "written, changed"
+            } else {
+                "written, new"
+            }
+        } else if changes.renamed_keys.contains(key) {
+            "deleted, renamed"
+        } else if changes.removed_keys.contains(key) {
+            if changes.prune {
+                "deleted, not in config"
+            } else {
+                "kept, not in config without prune"
+            }
+        } else if changes.kv_config.contains_key(key) {
+            "unchanged"
+        } else if is_meta_key(key) {
+            "kept, maintained by the tool"
+        } else if !Self::key_selected(&self.config, key) {
+            "filtered by include/exclude"
+        } else if self.config.write_manifest {
+            "kept, not in manifest"
+        } else {
+            "kept"
+        }
+    }
+
+    /// List keys to be removed and ask on stdin whether to delete them.
+    /// Deletion is declined without asking if stdout is not a terminal.
+    fn confirm_removal(&self, changes: &ConfigChanges) -> Result<bool, Error> {
+        let mut removed_keys: Vec<&String> = changes.removed_keys.iter().collect();
+        removed_keys.sort();
+        Self::confirm_deletion(&changes.service_config, &removed_keys)
+    }
+
+    /// Ask on the terminal to confirm deleting sorted keys of a service, false without a terminal
+    fn confirm_deletion(
+        service_config: &ServiceConfig,
+        removed_keys: &[&String],
+    ) -> Result<bool, Error> {
+        if !atty::is(atty::Stream::Stdout) {
+            warn!("Cannot ask to confirm deletion without a terminal");
+            return Ok(false);
+        }
+        let mut question = format!("Keys of {} to delete:\n", service_config);
+        for key in removed_keys {
+            question += &format!("- {}\n", key);
+        }
+        question += &format!("Delete {} keys? [y/N] ", removed_keys.len());
+        Self::ask_confirmation(&question, std::io::stdin().lock(), std::io::stdout().lock())
+    }
+
+    /// Write a question and read an answer, only `y` or `yes` confirms
+    fn ask_confirmation<R: BufRead, W: Write>(
+        question: &str,
+        mut input: R,
+        mut output: W,
+    ) -> Result<bool, Error> {
+        let io_error =
+            |err: std::io::Error| Error::Argument(format!("cannot ask to confirm: {}", err));
+        output.write_all(question.as_bytes()).map_err(io_error)?;
+        output.flush().map_err(io_error)?;
+        let mut answer = String::new();
+        input.read_line(&mut answer).map_err(io_error)?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    }
+
+    /// Waits until Consul is available, but no longer than the specified duration
+    fn wait_consul(&self, max_wait: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + max_wait;
+        let mut backoff = Duration::from_millis(500);
+        loop {
+            if self.consul_ready()? {
+                info!("Consul is alive");
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Unreachable);
+            }
+            info!("Cannot access Consul, retrying...");
+            std::thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(Duration::from_secs(5));
+        }
+    }
+
+    /// Check that Consul at any of the addresses is ready, starting with the active one,
+    /// and make the first ready address active
+    fn consul_ready(&self) -> Result<bool, Error> {
+        let active = self.active_address.load(Ordering::SeqCst);
+        for offset in 0..self.addresses.len() {
+            let index = (active + offset) % self.addresses.len();
+            if self.address_ready(&self.addresses[index])? {
+                if index != active {
+                    warn!("Failing over to Consul at {}", self.addresses[index]);
+                    self.active_address.store(index, Ordering::SeqCst);
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check that Consul at the address accepts connections and has elected a leader
+    fn address_ready(&self, address: &str) -> Result<bool, Error> {
+        let url = format!("{}/v1/status/leader", address);
+        match self.http_client.get(url).send() {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    debug!("Consul status is {}", response.status());
+                    return Ok(false);
+                }
+                // Leader is reported as an empty string while the cluster is starting
+                let leader = response.text()?;
+                Ok(!leader.trim().trim_matches('"').is_empty())
+            }
+            Err(err) if err.is_connect() && is_tls_error(&err) => Err(Error::Tls(format!(
+                "cannot establish a secure connection to {}: {}",
+                address,
+                error_chain(&err)
+            ))),
+            Err(err) if err.is_connect() || err.is_timeout() => Ok(false),
+            Err(err) => Err(Error::Http(err)),
+        }
+    }
+
+    /// Find config files at the configured path
+    fn enumerate_config_paths(config: &Config) -> Result<Vec<PathBuf>, Error> {
+        if let Some(matrix) = &config.matrix {
+            return Ok(vec![PathBuf::from(matrix)]);
+        }
+        if let Some(merged) = &config.merged {
+            return Ok(vec![PathBuf::from(merged)]);
+        }
+        if config.config_path == STDIN_PATH {
+            return Ok(vec![PathBuf::from(STDIN_PATH)]);
+        }
+        let root_path = Path::new(&config.config_path);
+        let mut config_paths: Vec<PathBuf> = if root_path.is_dir() {
+            let (files, mut errors) = Self::enumerate_files(root_path);
+            if files.is_empty() && !errors.is_empty() {
+                return Err(Error::ConfigFile(errors.remove(0)));
+            }
+            if !errors.is_empty() {
+                warn!(
+                    "Skipped {} unreadable directories, processing the files found",
+                    errors.len()
+                );
+            }
+            files
+        } else {
+            vec![root_path.to_path_buf()]
+        };
+        config_paths.sort();
+        info!("Processing {} files", config_paths.len());
+        Ok(config_paths)
+    }
+
+    /// Check whether env passes the env filter
+    fn env_matches(config: &Config, env: &str) -> bool {
+        match &config.filter_env {
+            Some(filter_env) => Self::name_matches(config, filter_env, env),
+            None => true,
+        }
+    }
+
+    /// Check whether service passes the service filter
+    fn service_matches(config: &Config, service: &str) -> bool {
+        match &config.filter_service {
+            Some(filter_service) => Self::name_matches(config, filter_service, service),
+            None => true,
+        }
+    }
+
+    /// Check whether service or env name passes a filter, ignoring case if configured
+    fn name_matches(config: &Config, filter: &str, name: &str) -> bool {
+        if config.ignore_case {
+            Self::filter_matches(&filter.to_lowercase(), &name.to_lowercase())
+        } else {
+            Self::filter_matches(filter, name)
+        }
+    }
+
+    /// Check whether value matches any of comma-separated patterns,
+    /// compared exactly or as globs with `*` and `?` wildcards
+    fn filter_matches(filter: &str, value: &str) -> bool {
+        filter
+            .split(',')
+            .map(str::trim)
+            .any(|pattern| Self::glob_matches(pattern.as_bytes(), value.as_bytes()))
+    }
+
+    /// Rename keys of a config file with `--rename-key`, failing if both names are in the file
+    fn apply_renames(config: &Config, kv_config: &mut KVConfig) -> Result<(), Error> {
+        if config.rename_keys.is_empty() {
+            return Ok(());
+        }
+        for (from, to) in &config.rename_keys {
+            if kv_config.contains_key(from) && kv_config.contains_key(to) {
+                return Err(Error::ConfigFormat(format!(
+                    "key {} is renamed to {}, which is also in config",
+                    from, to
+                )));
+            }
+        }
+        kv_config.rename_keys(|key| {
+            Ok(config
+                .rename_keys
+                .get(key)
+                .cloned()
+                .unwrap_or_else(|| key.to_string()))
+        })
+    }
+
+    fn published_key(config: &Config, key: &str) -> Result<String, Error> {
+        let prefix = |prefix: &Option<String>| {
+            prefix
+                .as_deref()
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty())
+        };
+        let key = match prefix(&config.key_prefix_strip) {
+            Some(strip) => key
+                .strip_prefix(&strip)
+                .and_then(|rest| rest.strip_prefix('/'))
+                .ok_or_else(|| {
+                    Error::ConfigFormat(format!(
+                        "key {} does not start with prefix {}/",
+                        key, strip
+                    ))
+                })?,
+            None => key,
+        };
+        Ok(match prefix(&config.key_prefix_add) {
+            Some(add) => format!("{}/{}", add, key),
+            None => key.to_string(),
+        })
+    }
+
+    /// Check whether key is selected by include and exclude key globs
+    fn key_selected(config: &Config, key: &str) -> bool {
+        let matches = |pattern: &String| Self::glob_matches(pattern.as_bytes(), key.as_bytes());
+        (config.include_keys.is_empty() || config.include_keys.iter().any(matches))
+            && !config.exclude_keys.iter().any(matches)
+    }
+
+    /// Match value against a glob pattern with `*` and `?` wildcards
+    fn glob_matches(pattern: &[u8], value: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => value.is_empty(),
+            Some((b'*', rest)) => {
+                (0..=value.len()).any(|pos| Self::glob_matches(rest, &value[pos..]))
+            }
+            Some((b'?', rest)) => !value.is_empty() && Self::glob_matches(rest, &value[1..]),
+            Some((c, rest)) => value.first() == Some(c) && Self::glob_matches(rest, &value[1..]),
+        }
+    }
+
+    /// Collect config files with their service and env, applying the service and env filters
+    fn collect_config_paths(config: &Config) -> Result<Vec<ConfigSource>, Error> {
+        let parsed_paths: Vec<ConfigSource> = Self::enumerate_config_paths(config)?
+            .into_iter()
+            .map(|config_path| Self::parse_config_paths(config, config_path))
+            .collect::<Result<Vec<_>, Error>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        info!("Found {} config paths", &parsed_paths.len());
+        let found_count = parsed_paths.len();
+        let filtered_parsed_paths: Vec<ConfigSource> = parsed_paths
+            .into_iter()
+            .filter(|source| {
+                let filtered_by = if !Self::service_matches(config, &source.service) {
+                    Some("service")
+                } else if !Self::env_matches(config, &source.env) {
+                    Some("env")
+                } else {
+                    None
+                };
+                if let Some(filtered_by) = filtered_by {
+                    if config.explain_skip {
+                        info!(
+                            "Config '{}': filtered by {}",
+                            source.path.to_str().unwrap_or(""),
+                            filtered_by
+                        );
+                    }
+                }
+                filtered_by.is_none()
+            })
+            .collect();
+        info!(
+            "Found {} filtered config paths",
+            &filtered_parsed_paths.len()
+        );
+        if filtered_parsed_paths.is_empty() {
+            // A typo in the path or filters of a pipeline must not look like a successful run
+            let reason = if found_count == 0 {
+                format!("found no config files in '{}'", config.config_path)
+            } else {
+                format!(
+                    "service and env filters excluded all {} config files",
+                    found_count
+                )
+            };
+            if config.fail_on_empty {
+                return Err(Error::NoConfigs(reason));
+            }
+            warn!("No config files to process: {}", reason);
+        }
+        Ok(filtered_parsed_paths)
+    }
+
+    /// Check that one config file parses and produces valid Consul keys
+    fn validate_file(config: &Config, config_path: PathBuf) -> Result<(), Error> {
+        for source in Self::parse_config_paths(config, config_path)? {
+            if !Self::service_matches(config, &source.service)
+                || !Self::env_matches(config, &source.env)
+            {
+                continue;
+            }
+            let mut kv_config = source.load(Self::line_syntax(config))?;
+            Self::warn_unknown_directives(&source, &kv_config);
+            kv_config.rename_keys(|key| Self::published_key(config, key))?;
+            Self::apply_renames(config, &mut kv_config)?;
+            if let Some(pattern) = Self::key_pattern(config)? {
+                kv_config.check_keys(&pattern)?;
+            }
+            if config.expand_env {
+                kv_config.expand_env(|name| std::env::var(name).ok())?;
+            }
+            kv_config.check_json_values(Self::json_pattern(config)?.as_ref(), false)?;
+            let service_config = Self::file_service_config(config, &source, &kv_config);
+            for key in kv_config.keys() {
+                service_config.consul_key(key)?;
+            }
+            if config.warn_double_base64 {
+                Self::warn_double_base64(&service_config, &kv_config);
+            }
+            debug!("Config for {} is valid", service_config);
+        }
+        Ok(())
+    }
+
+    /// Entry point of the validate command, checks config files without connecting to Consul
+    pub fn validate_files(config: &Config) -> Result<(), Error> {
+        Self::validate(config)?;
+        let config_paths = Self::enumerate_config_paths(config)?;
+        let configs_count = config_paths.len();
+        let mut invalid_count = 0;
+        for config_path in config_paths {
+            let path_str = config_path.to_str().unwrap_or("").to_string();
+            if let Err(err) = Self::validate_file(config, config_path) {
+                error!("Invalid config file '{}': {}", path_str, err);
+                invalid_count += 1;
+            }
+        }
+        if invalid_count > 0 {
+            return Err(Error::Invalid(invalid_count));
+        }
+        info!("All {} config files are valid", configs_count);
+        Ok(())
+    }
+
+    /// Service config of a loaded config file, using its `_template` unless the key template is fixed
+    fn file_service_config(
+        config: &Config,
+        source: &ConfigSource,
+        kv_config: &KVConfig,
+    ) -> ServiceConfig {
+        let service_config =
+            Self::service_config(config, source.service.clone(), source.env.clone());
+        match &kv_config.settings().template {
+            Some(template) if !config.key_template_fixed => {
+                service_config.with_template(template.clone())
+            }
+            _ => service_config,
+        }
+    }
+
+    /// Service config of a service and env with the key template and its variables
+    fn service_config(config: &Config, service: String, env: String) -> ServiceConfig {
+        ServiceConfig::new(config.key_template.clone(), service, env)
+            .with_vars(config.template_vars.clone())
+            .with_empty_segments(config.allow_empty_segments)
+    }
+
+    /// Service config for commands operating on a single service and env
+    fn target_service_config(&self) -> Result<ServiceConfig, Error> {
+        match (&self.config.service, &self.config.env) {
+            (Some(service), Some(env)) => Ok(Self::service_config(
+                &self.config,
+                service.clone(),
+                env.clone(),
+            )),
+            _ => Err(Error::Argument(String::from(
+                "service and env must be specified",
+            ))),
+        }
+    }
+
+    /// Entry point of the publish command, publishes all config files to each datacenter in turn
+    /// if several are given. Returns the number of key changes made, or pending in dryrun mode,
+    /// including deferred ones.
+    pub fn process(&self, dryrun: bool) -> Result<usize, Error> {
+        if dryrun {
+            warn!("Running in dryrun mode, no changes allowed");
+        }
+
+        let datacenters = Self::datacenters(&self.config);
+        if datacenters.len() <= 1 {
+            let results = self.publish_files(dryrun)?;
+            if self.config.output == OutputFormat::Json {
+                Self::print_json(&Self::stats_report(&results))?;
+            }
+            if !results.failed.is_empty() {
+                return Err(Error::FailedFiles(results.failed.len()));
+            }
+            return Ok(Self::changes_count(&results.total, self.config.prune));
+        }
+
+        let mut results = Vec::new();
+        for datacenter in &datacenters {
+            let _dc = logging::context("dc", datacenter);
+            info!("Publishing to datacenter {}", datacenter);
+            let config = Config {
+                datacenter: Some(datacenter.clone()),
+                ..self.config.clone()
+            };
+            let result =
+                Publisher::new(config).and_then(|publisher| publisher.publish_files(dryrun));
+            match result {
+                Err(err) if !self.config.continue_on_error => return Err(err),
+                result => results.push((datacenter, result)),
+            }
+        }
+
+        let mut changes = 0;
+        let mut reports = BTreeMap::new();
+        let mut failed = Vec::new();
+        for (datacenter, result) in results {
+            let report = match result {
+                Ok(results) => {
+                    info!(
+                        "Datacenter {}: found {} keys, updated {}, deleted {}, failed {} files",
+                        datacenter,
+                        results.total.count,
+                        results.total.changed,
+                        results.total.removed,
+                        results.failed.len()
+                    );
+                    if !results.failed.is_empty() {
+                        failed.push(datacenter.as_str());
+                    }
+                    changes += Self::changes_count(&results.total, self.config.prune);
+                    Self::stats_report(&results)
+                }
+                Err(err) => {
+                    error!("Datacenter {}: {}", datacenter, err);
+                    failed.push(datacenter.as_str());
+                    serde_json::json!({ "error": (err.to_string()) })
+                }
+            };
+            reports.insert(datacenter, report);
+        }
+        if self.config.output == OutputFormat::Json {
+            Self::print_json(&serde_json::json!({ "datacenters": reports }))?;
+        }
+        if !failed.is_empty() {
+            return Err(Error::Datacenters(failed.join(", ")));
+        }
+        Ok(changes)
+    }
+
+    /// Fingerprint of a config source published by this publisher, changing with its keys and
+    /// values as published, key directives, key prefix, datacenter and options processing values.
+    /// None if the source cannot be loaded.
+    fn fingerprint(&self, source: &ConfigSource) -> Option<String> {
+        let (kv_config, service_config, prune, _) = self.local_kv_config(source).ok()?;
+        let prefix = service_config.consul_key("").ok()?;
+        let mut options = Vec::new();
+        if prune {
+            options.push(String::from("prune"));
+        }
+        if self.config.compress {
+            options.push(format!(
+                "compress={}",
+                self.config
+                    .compress_threshold
+                    .unwrap_or(DEFAULT_COMPRESS_THRESHOLD)
+            ));
+        }
+        for (enabled, option) in [
+            (self.config.canonical_json, "canonical-json"),
+            (self.config.keep_quotes, "keep-quotes"),
+            (self.config.no_trim, "no-trim"),
+        ] {
+            if enabled {
+                options.push(String::from(option));
+            }
+        }
+        Some(format!(
+            "{}{} {}@{} {}",
+            kv_config.content_hash(),
+            kv_config.directives_hash(),
+            prefix,
+            self.config.datacenter.as_deref().unwrap_or(""),
+            options.join(",")
+        ))
+    }
+
+    /// Read fingerprints of published config files by their datacenters and names,
+    /// empty without a cache file
+    fn read_cache(&self) -> Result<BTreeMap<(String, String), String>, Error> {
+        let Some(cache_file) = &self.config.cache_file else {
+            return Ok(BTreeMap::new());
+        };
+        let content = match std::fs::read_to_string(cache_file) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(err) => return Err(Error::ConfigFile(err)),
+        };
+        Ok(content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let fingerprint = fields.next()?;
+                let datacenter = fields.next()?;
+                let name = fields.next()?;
+                Some((
+                    (datacenter.to_string(), name.to_string()),
+                    fingerprint.to_string(),
+                ))
+            })
+            .collect())
+    }
+
+    /// Write fingerprints of published config files as `fingerprint<TAB>datacenter<TAB>name` lines
+    fn write_cache(&self, cache: &BTreeMap<(String, String), String>) -> Result<(), Error> {
+        let Some(cache_file) = &self.config.cache_file else {
+            return Ok(());
+        };
+        let content: String = cache
+            .iter()
+            .map(|((datacenter, name), fingerprint)| {
+                format!("{}\t{}\t{}\n", fingerprint, datacenter, name)
+            })
+            .collect();
+        std::fs::write(cache_file, content).map_err(Error::ConfigFile)
+    }
+
+    /// Progress bar advancing per config file, hidden unless enabled in the config
+    fn progress_bar(&self, len: usize) -> ProgressBar {
+        if !self.config.progress {
+            return ProgressBar::hidden();
+        }
+        let progress = ProgressBar::new(len as u64);
+        if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} files [{elapsed}]") {
+            progress.set_style(style);
+        }
+        progress
+    }
+
+    /// Publish all config files to the datacenter of the publisher.
+    /// With `--continue-on-error` failed files are collected instead of stopping the run.
+    fn publish_files(&self, dryrun: bool) -> Result<PublishResults, Error> {
+        // Wait for Consul to be ready
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        // Handle each config file
+        let mut config_paths = Self::collect_config_paths(&self.config)?;
+        let mut cache = self.read_cache()?;
+        let mut fingerprints = HashMap::new();
+        let datacenter = self.config.datacenter.clone().unwrap_or_default();
+        let cache_key = |name: String| (datacenter.clone(), name);
+        if self.config.cache_file.is_some() {
+            config_paths.retain(|source| {
+                let Some(fingerprint) = self.fingerprint(source) else {
+                    return true;
+                };
+                let unchanged = cache.get(&cache_key(source.name())) == Some(&fingerprint);
+                if unchanged {
+                    info!("Skip unchanged config file {}", source.name());
+                }
+                fingerprints.insert(source.name(), fingerprint);
+                !unchanged
+            });
+        }
+        let configs_count = config_paths.len();
+        let progress = self.progress_bar(configs_count);
+        let results = if self.config.max_changes.is_some() || self.config.confirm {
+            // The limit of changes is shared by all config files of the run and questions
+            // must not interleave, so they go one by one
+            let mut remaining_changes = self.config.max_changes;
+            let mut results = Vec::new();
+            for source in &config_paths {
+                let result = self.handle_config(source, dryrun, remaining_changes);
+                progress.inc(1);
+                let (name, stats) = match result {
+                    Ok(result) => result,
+                    Err(err) if self.config.continue_on_error => {
+                        results.push((source.name(), Err(err)));
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let applied = if self.config.prune {
+                    stats.changed + stats.removed
+                } else {
+                    stats.changed + stats.deleted
+                };
+                remaining_changes =
+                    remaining_changes.map(|remaining| remaining.saturating_sub(applied));
+                results.push((name, Ok(stats)));
+            }
+            results
+        } else if self.config.continue_on_error {
+            parallel_map(&config_paths, self.config.jobs, |source| {
+                let result = self.handle_config(source, dryrun, None);
+                progress.inc(1);
+                Ok::<_, Error>((source.name(), result.map(|(_, stats)| stats)))
+            })?
+        } else {
+            parallel_map(&config_paths, self.config.jobs, |source| {
+                let result = self.handle_config(source, dryrun, None);
+                progress.inc(1);
+                let (name, stats) = result?;
+                Ok::<_, Error>((name, Ok(stats)))
+            })?
+        };
+        progress.finish_and_clear();
+        let mut total_stats = PublishStats::default();
+        let mut file_stats: BTreeMap<String, PublishStats> = BTreeMap::new();
+        let mut failed: BTreeMap<String, String> = BTreeMap::new();
+        for (name, result) in results {
+            match result {
+                Ok(stats) => {
+                    total_stats = total_stats + stats.clone();
+                    file_stats.insert(name, stats);
+                }
+                Err(err) => {
+                    error!("Failed to publish {}: {}", name, err);
+                    failed.insert(name, err.to_string());
+                }
+            }
+        }
+        if !dryrun && self.config.cache_file.is_some() {
+            for (name, fingerprint) in fingerprints {
+                // Files with deferred or skipped changes are published again on the next run
+                match file_stats.get(&name) {
+                    Some(stats) if stats.deferred == 0 && stats.skipped == 0 => {
+                        cache.insert(cache_key(name), fingerprint);
+                    }
+                    Some(_) => {
+                        cache.remove(&cache_key(name));
+                    }
+                    None if failed.contains_key(&name) => {
+                        cache.remove(&cache_key(name));
+                    }
+                    None => {}
+                }
+            }
+            self.write_cache(&cache)?;
+        }
+        info!(
+            "For {} files found {} keys, updated {}, deleted {}",
+            configs_count, total_stats.count, total_stats.changed, total_stats.removed,
+        );
+        let up_to_date = file_stats
+            .values()
+            .filter(|stats| Self::changes_count(stats, self.config.prune) == 0)
+            .count();
+        if up_to_date == configs_count && configs_count > 0 {
+            info!("No changes, all {} files are up to date", configs_count);
+        } else if up_to_date > 0 {
+            info!("{} of {} files are up to date", up_to_date, configs_count);
+        }
+        if !failed.is_empty() {
+            error!(
+                "Failed to publish {} of {} files",
+                failed.len(),
+                configs_count
+            );
+        }
+        if total_stats.deferred > 0 {
+            warn!(
+                "Deferred {} key changes, run again to converge",
+                total_stats.deferred
+            );
+        }
+        if total_stats.skipped > 0 {
+            info!("Skipped {} changed keys", total_stats.skipped);
+        }
+        Ok(PublishResults {
+            total: total_stats,
+            changes: std::mem::take(&mut *self.audit_log.lock().unwrap()),
+            files: file_stats,
+            failed,
+        })
+    }
+
+    /// JSON report of publishing statistics
+    fn stats_report(results: &PublishResults) -> serde_json::Value {
+        serde_json::json!({
+            "count": (results.total.count),
+            "changed": (results.total.changed),
+            "existing": (results.total.existing),
+            "removed": (results.total.removed),
+            "deferred": (results.total.deferred),
+            "skipped": (results.total.skipped),
+            "files": (results.files),
+            "changes": (results.changes),
+            "failed": (results.failed),
+        })
+    }
+
+    /// Print a JSON report to stdout
+    fn print_json(report: &serde_json::Value) -> Result<(), Error> {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(report)
+                .map_err(|err| Error::ConfigFormat(err.to_string()))?
+        );
+        Ok(())
+    }
+
+    /// Entry point of the publish command in watch mode.
+    /// Publishes config files and publishes them again whenever files under the config path change.
+    /// Failed runs are logged, so a file broken while being edited does not stop watching.
+    pub fn watch(&self, dryrun: bool) -> Result<(), Error> {
+        let watch_path = PathBuf::from(
+            self.config
+                .matrix
+                .as_deref()
+                .unwrap_or(&self.config.config_path),
+        );
+        if watch_path == Path::new(STDIN_PATH) {
+            return Err(Error::Argument(String::from(
+                "cannot watch the standard input",
+            )));
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).map_err(|err| Error::Watch(err.to_string()))?;
+        watcher
+            .watch(&watch_path, RecursiveMode::Recursive)
+            .map_err(|err| Error::Watch(format!("{}: {}", watch_path.display(), err)))?;
+        loop {
+            if let Err(err) = self.process(dryrun) {
+                error!("Publishing failed: {}", err);
+            }
+            info!("Watching '{}' for changes", watch_path.display());
+            Self::wait_changes(&receiver)?;
+        }
+    }
+
+    /// Block until files change and no more changes come for the debounce time
+    fn wait_changes(receiver: &Receiver<notify::Result<notify::Event>>) -> Result<(), Error> {
+        let disconnected = || Error::Watch(String::from("watcher stopped"));
+        loop {
+            match receiver.recv().map_err(|_| disconnected())? {
+                Ok(event) if Self::is_watched_change(&event) => break,
+                Ok(_) => {}
+                Err(err) => warn!("Watch error: {}", err),
+            }
+        }
+        loop {
+            match receiver.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+            }
+        }
+    }
+
+    /// Check whether a file event changes files, ignoring hidden and backup files of editors.
+    /// Any other file may be a config file, an included snippet or a value file.
+    fn is_watched_change(event: &notify::Event) -> bool {
+        let changes = event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove();
+        changes
+            && event.paths.iter().any(|path| {
+                let name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("");
+                !name.starts_with('.') && !name.ends_with('~')
+            })
+    }
+
+    /// Number of key changes in statistics, removals count only when pruning or deleted anyway
+    fn changes_count(stats: &PublishStats, prune: bool) -> usize {
+        let removed = if prune { stats.removed } else { stats.deleted };
+        stats.changed + removed + stats.deferred
+    }
+
+    /// Entry point of the diff command, prints keys that would be changed by publishing
+    pub fn diff(&self) -> Result<(), Error> {
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        for source in Self::collect_config_paths(&self.config)? {
+            let changes = self.compute_changes(&source)?;
+            self.print_changes(&changes)?;
+        }
+        Ok(())
+    }
+
+    /// Print changed and removed keys of a config to stdout with old and new values
+    fn print_changes(&self, changes: &ConfigChanges) -> Result<(), Error> {
+        if changes.changed_keys.is_empty()
+            && changes.removed_keys.is_empty()
+            && changes.renamed_keys.is_empty()
+        {
+            return Ok(());
+        }
+        // Print at once to keep lines of a config together when files are published concurrently
+        let mut output = format!("--- {}\n", changes.service_config);
+        let mut changed_keys: Vec<&String> = changes.changed_keys.iter().collect();
+        changed_keys.sort();
+        for key in changed_keys {
+            if let Some(old_value) = changes.existing_kvs.get(key) {
+                output += &format!("- {} = {}\n", key, self.postprocess_value(old_value));
+            }
+            let value = changes
+                .kv_config
+                .get(key)
+                .ok_or_else(|| Error::NotFound(key.to_string()))?;
+            output += &format!("+ {} = {}\n", key, self.postprocess_value(value));
+        }
+        let mut removed_keys: Vec<&String> =
+            changes.removed_keys.union(&changes.renamed_keys).collect();
+        removed_keys.sort();
+        for key in removed_keys {
+            // Values of removed keys are not read with --read-keys-first
+            match changes.existing_kvs.get(key) {
+                Some(old_value) => {
+                    output += &format!("- {} = {}\n", key, self.postprocess_value(old_value))
+                }
+                None => output += &format!("- {}\n", key),
+            }
+        }
+        print!("{}", output);
+        Ok(())
+    }
+
+    /// Drifted keys of a config for the JSON drift report, mapping each key to its state,
+    /// the value in Consul and the value in the config file, null if absent
+    fn drift_report(&self, changes: &ConfigChanges) -> serde_json::Value {
+        let mut keys = serde_json::Map::new();
+        for key in &changes.changed_keys {
+            let remote = changes
+                .existing_kvs
+                .get(key)
+                .map(|value| self.postprocess_value(value));
+            let local = changes
+                .kv_config
+                .get(key)
+                .map(|value| self.postprocess_value(value));
+            let state = if changes.remote_keys.contains(key) || remote.is_some() {
+                "changed"
+            } else {
+                "missing"
+            };
+            keys.insert(
+                key.clone(),
+                serde_json::json!({ "state": state, "consul": remote, "config": local }),
+            );
+        }
+        for key in changes.removed_keys.union(&changes.renamed_keys) {
+            let remote = changes
+                .existing_kvs
+                .get(key)
+                .map(|value| self.postprocess_value(value));
+            keys.insert(
+                key.clone(),
+                serde_json::json!({ "state": "removed", "consul": remote, "config": null }),
+            );
+        }
+        serde_json::Value::Object(keys)
+    }
+
+    /// Entry point of the verify command, fails if Consul is not in sync with config files.
+    /// With `report_only` drifted keys are printed and the check always succeeds.
+    /// With JSON output drifted keys are printed as a JSON object by config file instead of a diff.
+    pub fn verify(&self, report_only: bool) -> Result<(), Error> {
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let json = self.config.output == OutputFormat::Json;
+        let mut drift = 0;
+        let mut files = BTreeMap::new();
+        for source in Self::collect_config_paths(&self.config)? {
+            let changes = self.compute_changes(&source)?;
+            if json {
+                let keys = self.drift_report(&changes);
+                if keys.as_object().is_some_and(|keys| !keys.is_empty()) {
+                    files.insert(source.name(), keys);
+                }
+            } else if report_only {
+                self.print_changes(&changes)?;
+            }
+            drift += changes.changed_keys.len()
+                + changes.removed_keys.len()
+                + changes.renamed_keys.len();
+        }
+        if json {
+            Self::print_json(&serde_json::json!({ "drift": drift, "files": files }))?;
+        }
+        if drift > 0 {
+            if report_only {
+                warn!("Found {} keys out of sync with Consul", drift);
+                return Ok(());
+            }
+            return Err(Error::Drift(drift));
+        }
+        info!("Consul is in sync with config files");
+        Ok(())
+    }
+
+    /// Entry point of the export command, writes Consul keys as a KV config
+    pub fn export(&self, output: Option<&Path>, dryrun: bool) -> Result<(), Error> {
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let service_config = self.target_service_config()?;
+        let mut existing_kvs = self.read_kv_from_consul(&service_config)?;
+        Self::retain_exportable(&mut existing_kvs);
+        let content = Self::render_kvs(&existing_kvs, self.config.expand_env);
+        match output {
+            Some(path) if !dryrun => {
+                std::fs::write(path, content).map_err(Error::ConfigFile)?;
+                info!(
+                    "Exported {} keys of {} to '{}'",
+                    existing_kvs.len(),
+                    service_config,
+                    path.to_str().unwrap_or("")
+                );
+            }
+            _ => print!("{}", content),
+        }
+        Ok(())
+    }
+
+    /// Keep only keys that can be written to a KV config and read back unchanged
+    fn retain_exportable(kvs: &mut HashMap<String, String>) {
+        kvs.retain(|key, value| {
+            if is_meta_key(key) {
+                return false;
+            }
+            if value.trim().is_empty() {
+                warn!("Skip key {} with an empty value", key);
+                return false;
+            }
+            if value.contains('\n') || value.contains('\r') {
+                warn!("Skip key {} with a multi-line value", key);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Render key-value pairs sorted by key in KV config format, escaping comment marks,
+    /// trailing backslashes that would continue the line and leading `@` of file values.
+    /// With `expand_env` a `$` is escaped as `$$`, so it is not taken for a variable.
+    fn render_kvs(kvs: &HashMap<String, String>, expand_env: bool) -> String {
+        let mut keys: Vec<&String> = kvs.keys().collect();
+        keys.sort();
+        let mut content = String::new();
+        for key in keys {
+            let mut value = kvs[key].replace('#', "\\#");
+            if expand_env {
+                value = value.replace('$', "$$");
+            }
+            if value.starts_with('@') {
+                value.insert(0, '@');
+            }
+            let backslashes = value.len() - value.trim_end_matches('\\').len();
+            content.push_str(&format!(
+                "{} = {}{}\n",
+                key,
+                value,
+                "\\".repeat(backslashes)
+            ));
+        }
+        content
+    }
+
+    /// Entry point of the init command, creates a config file from keys stored in Consul
+    pub fn init(&self, directory: &Path, force: bool) -> Result<(), Error> {
+        let service_config = self.target_service_config()?;
+        let path = directory.join(format!(
+            "{}.{}.conf",
+            service_config.service(),
+            service_config.env()
+        ));
+        if path.exists() && !force {
+            return Err(Error::Argument(format!(
+                "config file '{}' already exists, use --force to overwrite",
+                path.to_str().unwrap_or("")
+            )));
+        }
+
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let mut existing_kvs = self.read_kv_from_consul(&service_config)?;
+        Self::retain_exportable(&mut existing_kvs);
+        let content = format!(
+            "# generated by consul_kv_config init with key template {}\n{}",
+            self.config.key_template,
+            Self::render_kvs(&existing_kvs, self.config.expand_env)
+        );
+        std::fs::write(&path, content).map_err(Error::ConfigFile)?;
+        info!(
+            "Initialized '{}' with {} keys of {}",
+            path.to_str().unwrap_or(""),
+            existing_kvs.len(),
+            service_config
+        );
+        Ok(())
+    }
+
+    /// Entry point of the list command, prints Consul keys of a service and env
+    pub fn list(&self) -> Result<(), Error> {
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let service_config = self.target_service_config()?;
+        let existing_kvs = self.read_kv_from_consul(&service_config)?;
+        let mut keys: Vec<&String> = existing_kvs.keys().collect();
+        keys.sort();
+        for key in keys {
+            println!("{}", key);
+        }
+        Ok(())
+    }
+
+    /// Entry point of the move command, relocates keys from the old template to the current one
+    pub fn move_keys(
+        &self,
+        old_template: &str,
+        delete_old: bool,
+        dryrun: bool,
+    ) -> Result<(), Error> {
+        if dryrun {
+            warn!("Running in dryrun mode, no changes allowed");
+        }
+        Self::validate_template(old_template)?;
+
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let service_config = self.target_service_config()?;
+        let old_service_config = service_config.with_template(old_template.to_string());
+        if old_service_config.consul_key("")? == service_config.consul_key("")? {
+            return Err(Error::Argument(String::from(
+                "old and new templates produce the same prefix",
+            )));
+        }
+        let old_kvs = self.read_kv_from_consul(&old_service_config)?;
+        let new_kvs = self.read_kv_from_consul(&service_config)?;
+        let mut old_keys: Vec<&String> = old_kvs.keys().collect();
+        old_keys.sort();
+
+        let mut moved = 0;
+        for key in old_keys {
+            if new_kvs.get(key) == old_kvs.get(key) {
+                debug!("Skip already moved key {}", key);
+                continue;
+            }
+            debug!("Move key {}", key);
+            moved += 1;
+            if !dryrun {
+                let kv_pair = consul::kv::KVPair {
+                    Key: service_config.consul_key(key)?,
+                    Value: old_kvs[key].clone(),
+                    ..Default::default()
+                };
+                self.with_retry(|| self.client_for(&service_config).put_raw(&kv_pair, None))?;
+            }
+        }
+
+        let deleted = if delete_old { old_kvs.len() } else { 0 };
+        if delete_old && !dryrun {
+            let old_keys: HashSet<String> = old_kvs.keys().cloned().collect();
+            self.remove_keys_from_consul(&old_keys, &old_keys, &old_service_config)?;
+        }
+        info!(
+            "Found {} keys for {} under old template, moved {}, deleted {}",
+            old_kvs.len(),
+            service_config,
+            moved,
+            deleted
+        );
+        Ok(())
+    }
+
+    /// Entry point of the expire command, removes keys written by the tool more than `max_age` ago
+    pub fn expire(&self, max_age: Duration, dryrun: bool) -> Result<(), Error> {
+        if dryrun {
+            warn!("Running in dryrun mode, no changes allowed");
+        }
+
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let service_config = self.target_service_config()?;
+        let existing_kvs = self.read_kv_from_consul(&service_config)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::Generic)?
+            .as_secs();
+        let expired_keys = Self::expired_keys(&existing_kvs, now, max_age);
+        for key in &expired_keys {
+            debug!("Expired key {}", key);
+        }
+        info!(
+            "Found {} keys for {}, {} expired keys and timestamps will be deleted",
+            existing_kvs.len(),
+            service_config,
+            expired_keys.len()
+        );
+        if !dryrun {
+            let all_keys: HashSet<String> = existing_kvs.keys().cloned().collect();
+            let expired_keys: HashSet<String> = expired_keys.into_iter().collect();
+            self.remove_keys_from_consul(&expired_keys, &all_keys, &service_config)?;
+            info!("Removed keys from consul");
+        }
+        Ok(())
+    }
+
+    /// Entry point of the delete-prefix command, removes the whole Consul tree of a service and env
+    /// including folders. Returns statistics with the number of deleted keys as removed.
+    pub fn delete_prefix(&self, dryrun: bool, confirm: bool) -> Result<PublishStats, Error> {
+        if dryrun {
+            warn!("Running in dryrun mode, no changes allowed");
+        }
+
+        let service_config = self.target_service_config()?;
+        let consul_prefix = service_config.consul_key("")?;
+        // A template rendering to the root would wipe every key in Consul
+        if consul_prefix.trim_matches('/').is_empty() {
+            return Err(Error::Template(format!(
+                "refusing to delete keys under prefix '{}'",
+                consul_prefix
+            )));
+        }
+
+        self.wait_consul(Duration::from_secs(self.config.timeout))?;
+
+        let existing_kvs = self.read_kv_from_consul(&service_config)?;
+        let mut existing_keys: Vec<&String> = existing_kvs.keys().collect();
+        existing_keys.sort();
+        info!(
+            "Found {} keys under {} for {}",
+            existing_keys.len(),
+            consul_prefix,
+            service_config
+        );
+        let mut stats = PublishStats {
+            existing: existing_keys.len(),
+            ..PublishStats::default()
+        };
+        if existing_keys.is_empty() {
+            return Ok(stats);
+        }
+        if dryrun {
+            for key in &existing_keys {
+                info!("Would delete key {}", key);
+            }
+            stats.removed = existing_keys.len();
+            return Ok(stats);
+        }
+        if confirm && !Self::confirm_deletion(&service_config, &existing_keys)? {
+            warn!("Kept keys of {}, deletion is not confirmed", service_config);
+            return Ok(stats);
+        }
+        self.delete_recursive(&service_config, "")?;
+        stats.removed = existing_keys.len();
+        info!("Removed {} keys from consul", stats.removed);
+        Ok(stats)
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_filename() {
+        let pattern = Publisher::filename_pattern(&Config::default()).unwrap();
+        let mut res = Publisher::deduce_service_env_from_filename(
+            &"myservice.myenv.conf".to_owned(),
+            &pattern,
+        );
+        assert_eq!(res.unwrap(), ("myservice".to_string(), "myenv".to_string()));
+        assert!(Publisher::deduce_service_env_from_filename(
+            &"myservice.myenv.txt".to_owned(),
+            &pattern
+        )
+        .is_err());
+        assert!(
+            Publisher::deduce_service_env_from_filename(&"myenv.conf".to_owned(), &pattern)
+                .is_err()
+        );
+        assert!(
+            Publisher::deduce_service_env_from_filename(&"..conf".to_owned(), &pattern).is_err()
+        );
+        assert!(
+            Publisher::deduce_service_env_from_filename(&"s.e.conf".to_owned(), &pattern).is_ok()
+        );
+        assert!(
+            Publisher::deduce_service_env_from_filename(&"s.e.yaml".to_owned(), &pattern).is_ok()
+        );
+        assert!(
+            Publisher::deduce_service_env_from_filename(&"s.e.json".to_owned(), &pattern).is_ok()
+        );
+        assert!(Publisher::deduce_service_env_from_filename(
+            &"s.e.conf.local".to_owned(),
+            &pattern
+        )
+        .is_err());
+        res = Publisher::deduce_service_env_from_filename(
+            &"my_service.my_env123.conf".to_owned(),
+            &pattern,
+        );
+        assert_eq!(
+            res.unwrap(),
+            ("my_service".to_string(), "my_env123".to_string())
+        );
+        assert!(Publisher::deduce_service_env_from_filename(
+            &"myservice.second.myenv.conf".to_owned(),
+            &pattern
+        )
+        .is_err());
+        res = Publisher::deduce_service_env_from_filename(
+            &"my-service.my-env123.conf".to_owned(),
+            &pattern,
+        );
+        assert_eq!(
+            res.unwrap(),
+            ("my-service".to_string(), "my-env123".to_string())
+        );
+        res = Publisher::deduce_service_env_from_filename(&"team/s.e.conf".to_owned(), &pattern);
+        assert_eq!(res.unwrap(), ("s".to_string(), "e".to_string()));
+
+        let config = Config {
+            filename_pattern: Some(String::from(r"^(?P<env>[a-z]+)/(?P<service>[a-z]+)\.conf$")),
+            ..Default::default()
+        };
+        let pattern = Publisher::filename_pattern(&config).unwrap();
+        res = Publisher::deduce_service_env_from_filename(&"prod/auth.conf".to_owned(), &pattern);
+        assert_eq!(res.unwrap(), ("auth".to_string(), "prod".to_string()));
+        let config = Config {
+            filename_pattern: Some(String::from(r"^(?P<service>[a-z]+)\.conf$")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Publisher::filename_pattern(&config),
+            Err(Error::Template(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        let parse = |layout: &str, path: &str| {
+            let config = Config {
+                layout: Some(layout.to_string()),
+                ..Default::default()
+            };
+            let pattern = Publisher::filename_pattern(&config).unwrap();
+            Publisher::deduce_service_env_from_filename(&path.to_owned(), &pattern)
+        };
+        assert_eq!(
+            parse("env/service", "envs/prod/auth.conf").unwrap(),
+            ("auth".to_string(), "prod".to_string())
+        );
+        assert_eq!(
+            parse("env/service", "stage/billing-api.yaml").unwrap(),
+            ("billing-api".to_string(), "stage".to_string())
+        );
+        assert_eq!(
+            parse("service/env", "auth/prod.conf").unwrap(),
+            ("auth".to_string(), "prod".to_string())
+        );
+        assert!(parse("env/service", "auth.prod.conf").is_err());
+        for layout in ["env", "env/env", "service/env/team", "env/name"] {
+            let config = Config {
+                layout: Some(layout.to_string()),
+                ..Default::default()
+            };
+            assert!(matches!(
+                Publisher::filename_pattern(&config),
+                Err(Error::Argument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_removable_prefixes() {
+        let existing: HashSet<String> = [
+            "a",
+            "old/x",
+            "old/y",
+            "old/sub/z",
+            "mixed/x",
+            "mixed/y",
+            "single/x",
+            "_order",
+        ]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+        let removed: Vec<String> = ["old/x", "old/y", "old/sub/z", "mixed/x", "single/x"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        let removed: HashSet<&String> = removed.iter().collect();
+        assert_eq!(
+            Publisher::removable_prefixes(&removed, &existing),
+            vec!["old/".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_render_kvs() {
+        let mut kvs = HashMap::from([
+            ("b".to_string(), "x = y".to_string()),
+            ("a".to_string(), "1".to_string()),
+            ("c".to_string(), "#fff".to_string()),
+            ("lines".to_string(), "1\n2".to_string()),
+            ("empty".to_string(), "".to_string()),
+            ("_order".to_string(), "[]".to_string()),
+        ]);
+        Publisher::retain_exportable(&mut kvs);
+        let content = Publisher::render_kvs(&kvs, false);
+        assert_eq!(content, "a = 1\nb = x = y\nc = \\#fff\n");
+    }
+
+    #[test]
+    fn test_render_kvs_round_trip() {
+        let kvs = HashMap::from([
+            ("dir".to_string(), "C:\\temp\\".to_string()),
+            ("escaped".to_string(), "a\\\\".to_string()),
+            ("color".to_string(), "#fff".to_string()),
+            ("mark".to_string(), "a\\#".to_string()),
+            ("url".to_string(), "http://host/?a=b".to_string()),
+            ("team".to_string(), "@team".to_string()),
+            ("template".to_string(), "${HOME}/$x".to_string()),
+        ]);
+        let content = Publisher::render_kvs(&kvs, false);
+        assert!(content.contains("dir = C:\\temp\\\\\n"));
+        let root = std::env::temp_dir().join(format!(
+            "consul_kv_config_round_trip_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), &content).unwrap();
+        let kv_config = KVConfig::new(&root.join("app.prod.conf"));
+        // Variables are kept literally when published with --expand-env
+        std::fs::write(
+            root.join("app.prod.conf"),
+            Publisher::render_kvs(&kvs, true),
+        )
+        .unwrap();
+        let mut expanded = KVConfig::new(&root.join("app.prod.conf")).unwrap();
+        expanded.expand_env(|_| None).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(*kv_config.unwrap(), kvs);
+        assert_eq!(*expanded, kvs);
+    }
+
+    #[test]
+    fn test_is_double_base64() {
+        assert!(Publisher::is_double_base64("aGVsbG8gd29ybGQ="));
+        assert!(Publisher::is_double_base64("c2VjcmV0IHZhbHVl"));
+        assert!(!Publisher::is_double_base64("true"));
+        assert!(!Publisher::is_double_base64("plain text"));
+        assert!(!Publisher::is_double_base64("localhost"));
+        assert!(!Publisher::is_double_base64("AAECAwQFBgc="));
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let calls = std::cell::Cell::new(0);
+        let flaky = || {
+            calls.set(calls.get() + 1);
+            if calls.get() <= 2 {
+                Err(String::from("unavailable"))
+            } else {
+                Ok(calls.get())
+            }
+        };
+        let result = retry_with_backoff(3, Duration::from_millis(1), |_| true, flaky);
+        assert_eq!(result, Ok(3));
+
+        calls.set(0);
+        let result = retry_with_backoff(1, Duration::from_millis(1), |_| true, flaky);
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        let result = retry_with_backoff(3, Duration::from_millis(1), |_| false, flaky);
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let items: Vec<u64> = (0..10).collect();
+        // Later items finish first, results still follow the order of items
+        let square = |item: &u64| -> Result<u64, u64> {
+            std::thread::sleep(Duration::from_millis(10 - item));
+            Ok(item * item)
+        };
+        let expected: Vec<u64> = items.iter().map(|item| item * item).collect();
+        assert_eq!(parallel_map(&items, 4, square), Ok(expected.clone()));
+        assert_eq!(parallel_map(&items, 1, square), Ok(expected));
+
+        let fail_odd = |item: &u64| -> Result<u64, u64> {
+            std::thread::sleep(Duration::from_millis(10 - item));
+            if item % 2 == 1 {
+                Err(*item)
+            } else {
+                Ok(*item)
+            }
+        };
+        assert_eq!(parallel_map(&items, 4, fail_odd), Err(1));
+    }
+
+    #[test]
+    fn test_key_selected() {
+        let mut config = Config {
+            exclude_keys: vec![String::from("secret_*")],
+            ..Config::default()
+        };
+        let mut kv_config =
+            KVConfig::from_reader("secret_token = x\nname = svc\n".as_bytes()).unwrap();
+        kv_config.retain_keys(|key| Publisher::key_selected(&config, key));
+        assert_eq!(kv_config.get("secret_token"), None);
+        assert_eq!(kv_config.get("name").unwrap(), "svc");
+        // Excluded key present in Consul only is not deleted
+        let existing_keys: HashSet<String> = ["secret_old", "name", "stale"]
+            .iter()
+            .map(|key| key.to_string())
+            .filter(|key| Publisher::key_selected(&config, key))
+            .collect();
+        let removed_keys = kv_config.missing_keys(&existing_keys);
+        assert_eq!(removed_keys, HashSet::from([String::from("stale")]));
+
+        config.include_keys = vec![String::from("feature_*"), String::from("name")];
+        assert!(Publisher::key_selected(&config, "feature_x"));
+        assert!(Publisher::key_selected(&config, "name"));
+        assert!(!Publisher::key_selected(&config, "timeout"));
+        config.exclude_keys = vec![String::from("feature_beta")];
+        assert!(!Publisher::key_selected(&config, "feature_beta"));
+    }
+
+    #[test]
+    fn test_failover() {
+        let publisher = Publisher::new(Config {
+            consul_addr: String::from("http://a:8500/, http://b:8500,,"),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(publisher.addresses, vec!["http://a:8500", "http://b:8500"]);
+        // Connection errors move to the next address, other errors do not
+        let call = || match publisher.consul_addr() {
+            "http://a:8500" => Err(String::from("connect")),
+            address => Ok(address.to_string()),
+        };
+        let result = publisher.with_failover(|err: &String| err == "connect", call);
+        assert_eq!(result, Ok(String::from("http://b:8500")));
+        let result =
+            publisher.with_failover(|_: &String| false, || Err::<(), _>(String::from("denied")));
+        assert_eq!(result, Err(String::from("denied")));
+        assert_eq!(publisher.consul_addr(), "http://b:8500");
+        // Each address is tried once
+        let calls = std::cell::Cell::new(0);
+        let result = publisher.with_failover(
+            |_: &String| true,
+            || {
+                calls.set(calls.get() + 1);
+                Err::<(), _>(String::from("connect"))
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_raw_requests_retry_server_errors() {
+        // Every other request fails with a server error
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let consul_addr = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for (number, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let target = request_line.split_whitespace().nth(1).unwrap_or("");
+                recorded.lock().unwrap().push(target.to_string());
+                let (status, body) = if number % 2 == 0 {
+                    (500, String::from("rpc error"))
+                } else if target.ends_with("?keys") {
+                    (200, String::from("[\"config/app/prod/host\"]"))
+                } else {
+                    (200, String::from("true"))
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            key_template: String::from("config/{service}/{env}/{key}"),
+            retries: 1,
+            retry_delay: 1,
+            timeout: 5,
+            ..Config::default()
+        })
+        .unwrap();
+        let service_config = ServiceConfig::new(
+            String::from("config/{service}/{env}/{key}"),
+            String::from("app"),
+            String::from("prod"),
+        );
+
+        let keys = publisher.list_remote_keys(&service_config).unwrap();
+        assert_eq!(keys, HashSet::from([String::from("host")]));
+        assert!(publisher
+            .put_cas(&service_config, "config/app/prod/host", "db", 0, 1)
+            .unwrap());
+        assert!(publisher.delete_recursive(&service_config, "db/").is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_ask_confirmation() {
+        let ask = |answer: &str| {
+            let mut output = Vec::new();
+            let confirmed =
+                Publisher::ask_confirmation("Delete 2 keys? ", answer.as_bytes(), &mut output);
+            assert_eq!(output, b"Delete 2 keys? ");
+            confirmed.unwrap()
+        };
+        assert!(ask("y\n"));
+        assert!(ask(" Yes \n"));
+        assert!(!ask("\n"));
+        assert!(!ask("no\n"));
+        assert!(!ask(""));
+    }
+
+    #[test]
+    fn test_check_written() {
+        assert!(Publisher::check_written("config/a", "value", Some("value")).is_ok());
+        let reason =
+            |stored: Option<&str>| match Publisher::check_written("config/a", "value", stored) {
+                Err(Error::WriteMismatch(key, reason)) if key == "config/a" => reason,
+                result => panic!("unexpected result {:?}", result),
+            };
+        assert_eq!(reason(None), "key is missing");
+        assert_eq!(reason(Some("val")), "read 3 bytes instead of 5");
+        assert_eq!(reason(Some("vaLue")), "values differ at byte 2");
+    }
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(
+            Publisher::decode_value("app/key", "dmFsdWU=").unwrap(),
+            "value"
+        );
+        assert!(matches!(
+            Publisher::decode_value("app/key", "not base64!"),
+            Err(Error::Decode(key)) if key == "app/key"
+        ));
+        assert!(matches!(
+            Publisher::decode_value("app/key", "//4="),
+            Err(Error::InvalidUtf8(key)) if key == "app/key"
+        ));
+    }
+
+    #[test]
+    fn test_compress_value() {
+        let value = "{\"data\": \"".to_string() + &"x".repeat(1000) + "\"}";
+        let stored = Publisher::compress_value(&value).unwrap();
+        assert_ne!(stored, value);
+        assert_eq!(
+            Publisher::decompress_value("app/key", &stored).unwrap(),
+            value
+        );
+        assert!(matches!(
+            Publisher::decompress_value("app/key", "bm90IGd6aXA="),
+            Err(Error::Decompress(key)) if key == "app/key"
+        ));
+
+        let publisher = Publisher::new(Config {
+            key_template: String::from("config/{service}/{env}/{key}"),
+            consul_addr: String::from("http://localhost:8500"),
+            compress: true,
+            compress_threshold: Some(100),
+            ..Config::default()
+        })
+        .unwrap();
+        let small = publisher.stored_value("key", "x".repeat(100), 3).unwrap();
+        assert_eq!(small, ("x".repeat(100), 3));
+        let (stored, flags) = publisher.stored_value("key", value.clone(), 0).unwrap();
+        assert_eq!(flags, COMPRESSED_FLAGS);
+        assert_eq!(Publisher::decompress_value("key", &stored).unwrap(), value);
+        assert!(matches!(
+            publisher.stored_value("key", value, 3),
+            Err(Error::ConfigFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_value_size() {
+        assert!(Publisher::check_value_size("a", "12345", 5).is_ok());
+        let err = Publisher::check_value_size("cert", "123456", 5).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error in KV config: value of key cert has 6 bytes, more than the maximum of 5 bytes"
+        );
+        // Size is counted in bytes, not characters
+        assert!(Publisher::check_value_size("a", "ééé", 5).is_err());
+    }
+
+    #[test]
+    fn test_read_token_file() {
+        let path =
+            std::env::temp_dir().join(format!("consul_kv_config_token_{}", std::process::id()));
+        std::fs::write(&path, "  secret-token\n").unwrap();
+        let token = Publisher::read_token_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(token.unwrap(), "secret-token");
+        let err = Publisher::read_token_file(&path).unwrap_err();
+        assert!(matches!(&err, Error::ConfigFile(_)));
+        assert!(err.to_string().contains("cannot read token file"));
+    }
+
+    #[test]
+    fn test_changes_count() {
+        assert_eq!(Publisher::changes_count(&PublishStats::default(), true), 0);
+        let stats = PublishStats {
+            count: 10,
+            existing: 8,
+            changed: 2,
+            removed: 3,
+            deferred: 1,
+            ..PublishStats::default()
+        };
+        assert_eq!(Publisher::changes_count(&stats, false), 3);
+        assert_eq!(Publisher::changes_count(&stats, true), 6);
+    }
+
+    #[test]
+    fn test_is_watched_change() {
+        use notify::event::{AccessKind, CreateKind, ModifyKind};
+        use notify::{Event, EventKind};
+        let event = |kind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert!(Publisher::is_watched_change(&event(
+            modify,
+            "configs/svc.prod.conf"
+        )));
+        assert!(Publisher::is_watched_change(&event(
+            EventKind::Create(CreateKind::File),
+            "configs/shared/db.inc"
+        )));
+        assert!(!Publisher::is_watched_change(&event(
+            EventKind::Access(AccessKind::Any),
+            "configs/svc.prod.conf"
+        )));
+        assert!(!Publisher::is_watched_change(&event(
+            modify,
+            "configs/.svc.prod.conf.swp"
+        )));
+        assert!(!Publisher::is_watched_change(&event(
+            modify,
+            "configs/svc.prod.conf~"
+        )));
+    }
+
+    #[test]
+    fn test_trim_value() {
+        assert_eq!(
+            Publisher::trim_value("  \"secret\"  ", true, true),
+            "secret"
+        );
+        assert_eq!(
+            Publisher::trim_value("  \"secret\"  ", false, false),
+            "  \"secret\"  "
+        );
+        assert_eq!(
+            Publisher::trim_value("  \"secret\"  ", true, false),
+            "\"secret\""
+        );
+        assert_eq!(
+            Publisher::trim_value("\"  secret  \"", false, true),
+            "  secret  "
+        );
+    }
+
+    #[test]
+    fn test_owned_keys() {
+        assert!(Publisher::owned_keys(None).unwrap().is_empty());
+        let manifest = String::from(r#"["a", "b"]"#);
+        let owned_keys = Publisher::owned_keys(Some(&manifest)).unwrap();
+        assert_eq!(owned_keys.len(), 2);
+        assert!(owned_keys.contains("a"));
+        assert!(Publisher::owned_keys(Some(&String::from("a,b"))).is_err());
+    }
+
+    #[test]
+    fn test_published_key() {
+        let config = |strip: Option<&str>, add: Option<&str>| Config {
+            key_prefix_strip: strip.map(String::from),
+            key_prefix_add: add.map(String::from),
+            ..Config::default()
+        };
+        let plain = config(None, None);
+        assert_eq!(
+            Publisher::published_key(&plain, "app/db/host").unwrap(),
+            "app/db/host"
+        );
+        let strip = config(Some("app/"), None);
+        assert_eq!(
+            Publisher::published_key(&strip, "app/db/host").unwrap(),
+            "db/host"
+        );
+        assert!(matches!(
+            Publisher::published_key(&strip, "application/host"),
+            Err(Error::ConfigFormat(msg)) if msg.contains("application/host")
+        ));
+        let both = config(Some("app"), Some("/v2/"));
+        assert_eq!(
+            Publisher::published_key(&both, "app/db/host").unwrap(),
+            "v2/db/host"
+        );
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        assert!(Publisher::filter_matches("prod", "prod"));
+        assert!(!Publisher::filter_matches("prod", "prod-eu"));
+        assert!(Publisher::filter_matches("prod*", "prod-eu"));
+        assert!(Publisher::filter_matches("prod*", "prod"));
+        assert!(Publisher::filter_matches("staging,qa", "qa"));
+        assert!(!Publisher::filter_matches("staging,qa", "dev"));
+        assert!(Publisher::filter_matches("dev, stag*", "staging"));
+        assert!(Publisher::filter_matches("qa?", "qa1"));
+        assert!(!Publisher::filter_matches("qa?", "qa"));
+        assert!(Publisher::filter_matches("*-eu", "prod-eu"));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_case_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["Auth.Prod.conf", "billing.PROD.conf", "cart.staging.conf"] {
+            std::fs::write(root.join(name), "host = db\n").unwrap();
+        }
+        let mut config = Config {
+            config_path: root.to_str().unwrap().to_string(),
+            filter_env: Some(String::from("prod")),
+            ..Config::default()
+        };
+        let exact = Publisher::collect_config_paths(&config);
+        config.ignore_case = true;
+        let ignored = Publisher::collect_config_paths(&config);
+        config.lowercase_names = true;
+        let lowercased = Publisher::collect_config_paths(&config);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(exact.unwrap().is_empty());
+        let names = |sources: Vec<ConfigSource>| {
+            let mut names: Vec<(String, String)> = sources
+                .into_iter()
+                .map(|source| (source.service, source.env))
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names(ignored.unwrap()),
+            vec![
+                (String::from("Auth"), String::from("Prod")),
+                (String::from("billing"), String::from("PROD"))
+            ]
+        );
+        assert_eq!(
+            names(lowercased.unwrap()),
+            vec![
+                (String::from("auth"), String::from("prod")),
+                (String::from("billing"), String::from("prod"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limit_changes() {
+        let to_set =
+            |keys: &[&str]| -> HashSet<String> { keys.iter().map(|key| key.to_string()).collect() };
+        let mut changed = to_set(&["c", "a", "b"]);
+        let mut removed = to_set(&["y", "x"]);
+        assert_eq!(
+            Publisher::limit_changes(&mut changed, &mut removed, 4),
+            to_set(&["y"])
+        );
+        assert_eq!(changed, to_set(&["a", "b", "c"]));
+        assert_eq!(removed, to_set(&["x"]));
+
+        assert_eq!(
+            Publisher::limit_changes(&mut changed, &mut removed, 2),
+            to_set(&["c", "x"])
+        );
+        assert_eq!(changed, to_set(&["a", "b"]));
+        assert!(removed.is_empty());
+
+        assert!(Publisher::limit_changes(&mut changed, &mut removed, 5).is_empty());
+        assert_eq!(changed.len(), 2);
+    }
+
+    #[test]
+    fn test_validate_reversible_template() {
+        assert!(
+            Publisher::validate_reversible_template("config/service/{service}/{env}/{key}").is_ok()
+        );
+        assert!(Publisher::validate_reversible_template("{env}/cfg-{service}/{key}").is_ok());
+        assert!(Publisher::validate_reversible_template("config/{env}/{key}").is_err());
+        assert!(Publisher::validate_reversible_template("config/{service}-{env}/{key}").is_err());
+        assert!(
+            Publisher::validate_reversible_template("{service}/{env}/{service}/{key}").is_err()
+        );
+    }
+
+    #[test]
+    fn test_datacenters() {
+        let datacenters = |datacenter: Option<&str>| {
+            Publisher::datacenters(&Config {
+                datacenter: datacenter.map(String::from),
+                ..Default::default()
+            })
+        };
+        assert!(datacenters(None).is_empty());
+        assert_eq!(datacenters(Some("dc1")), vec!["dc1"]);
+        assert_eq!(
+            datacenters(Some("dc1, dc2,,dc3")),
+            vec!["dc1", "dc2", "dc3"]
+        );
+    }
+
+    #[test]
+    fn test_single_value() {
+        let config = |key_template: &str, write_version: bool| Config {
+            key_template: key_template.to_string(),
+            single_value: true,
+            write_version,
+            ..Default::default()
         };
-        config_paths.sort();
-        let configs_count = &config_paths.len();
-        // Handle each config file
-        info!("Processing {} files", configs_count);
-        let parsed_paths: Vec<(&Path, String, String)> = config_paths
-            .iter()
-            .map(|config_path| self.parse_config_paths(config_path))
-            .collect::<Result<Vec<_>, Error>>()?;
-        info!("Found {} config paths", &parsed_paths.len());
-        let filtered_parsed_paths: Vec<(&Path, String, String)> = parsed_paths
+        assert!(Publisher::validate(&config("config/{service}/{env}", false)).is_ok());
+        assert!(matches!(
+            Publisher::validate(&config("config/{service}/{env}/{key}", false)),
+            Err(Error::Template(_))
+        ));
+        assert!(matches!(
+            Publisher::validate(&config("config/{service}/{env}", true)),
+            Err(Error::Argument(_))
+        ));
+
+        let kv_config = KVConfig::from_reader("value=42".as_bytes()).unwrap();
+        assert_eq!(Publisher::single_value_key(&kv_config).unwrap(), "value");
+        let kv_config = KVConfig::from_reader("a=1\nb=2".as_bytes()).unwrap();
+        assert!(matches!(
+            Publisher::single_value_key(&kv_config),
+            Err(Error::ConfigFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_expired_keys() {
+        let kvs = HashMap::from([
+            ("old".to_string(), "1".to_string()),
+            ("_ts.old".to_string(), "1000".to_string()),
+            ("fresh".to_string(), "2".to_string()),
+            ("_ts.fresh".to_string(), "1900".to_string()),
+            ("unmanaged".to_string(), "3".to_string()),
+            ("_ts.gone".to_string(), "100".to_string()),
+            ("_ts.broken".to_string(), "yesterday".to_string()),
+        ]);
+        assert_eq!(
+            Publisher::expired_keys(&kvs, 2000, Duration::from_secs(500)),
+            vec!["_ts.gone", "_ts.old", "old"]
+        );
+    }
+
+    #[test]
+    fn test_is_structured() {
+        assert!(Publisher::is_structured(r#"{"a": 1}"#));
+        assert!(Publisher::is_structured("[1, 2]"));
+        assert!(!Publisher::is_structured("42"));
+        assert!(!Publisher::is_structured("true"));
+        assert!(!Publisher::is_structured("plain text"));
+        assert!(!Publisher::is_structured("{broken"));
+    }
+
+    #[test]
+    fn test_parse_service_dir() {
+        assert_eq!(
+            Publisher::deduce_service_env_from_dir(Path::new("configs/auth/prod.conf")).unwrap(),
+            ("auth".to_string(), "prod".to_string())
+        );
+        assert_eq!(
+            Publisher::deduce_service_env_from_dir(Path::new("auth/staging.yaml")).unwrap(),
+            ("auth".to_string(), "staging".to_string())
+        );
+        assert!(Publisher::deduce_service_env_from_dir(Path::new("prod.conf")).is_err());
+
+        let config = Config {
+            service_from_dir: true,
+            env: Some(String::from("qa")),
+            ..Default::default()
+        };
+        let sources =
+            Publisher::parse_config_paths(&config, PathBuf::from("configs/auth/prod.conf"))
+                .unwrap();
+        assert_eq!(sources[0].service, "auth");
+        assert_eq!(sources[0].env, "qa");
+    }
+
+    #[test]
+    fn test_parse_stdin_path() {
+        let config = Config::default();
+        assert!(matches!(
+            Publisher::parse_config_paths(&config, PathBuf::from(STDIN_PATH)),
+            Err(Error::Argument(_))
+        ));
+        let config = Config {
+            service: Some(String::from("auth")),
+            env: Some(String::from("prod")),
+            ..Default::default()
+        };
+        let sources = Publisher::parse_config_paths(&config, PathBuf::from(STDIN_PATH)).unwrap();
+        assert_eq!(sources[0].service, "auth");
+        assert!(sources[0].base.is_none());
+    }
+
+    #[test]
+    fn test_collect_empty() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_empty_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut config = Config {
+            config_path: root.to_str().unwrap().to_string(),
+            fail_on_empty: true,
+            ..Config::default()
+        };
+        let no_files = Publisher::collect_config_paths(&config);
+        std::fs::write(root.join("svc.prod.conf"), "host = db\n").unwrap();
+        config.filter_env = Some(String::from("stage"));
+        let filtered = Publisher::collect_config_paths(&config);
+        config.fail_on_empty = false;
+        let warned = Publisher::collect_config_paths(&config);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(no_files, Err(Error::NoConfigs(reason)) if reason.contains("found no")));
+        assert!(matches!(filtered, Err(Error::NoConfigs(reason)) if reason.contains("filters")));
+        assert!(warned.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_base_config() {
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_base_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("svc.base.conf"), "host = db\nport = 5432\n").unwrap();
+        std::fs::write(root.join("svc.prod.conf"), "host = prod-db\n").unwrap();
+        let config = Config::default();
+        let base_sources = Publisher::parse_config_paths(&config, root.join("svc.base.conf"));
+        let sources = Publisher::parse_config_paths(&config, root.join("svc.prod.conf"));
+        let kv_config = sources.as_ref().unwrap()[0].load(LineSyntax::default());
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(base_sources.unwrap().is_empty());
+        assert_eq!(sources.unwrap()[0].base, Some(root.join("svc.base.conf")));
+        let kv_config = kv_config.unwrap();
+        assert_eq!(kv_config.get("host").unwrap(), "prod-db");
+        assert_eq!(kv_config.get("port").unwrap(), "5432");
+    }
+
+    #[test]
+    fn test_enumerate_files_recursive() {
+        let root = std::env::temp_dir().join(format!("consul_kv_config_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("team/nested")).unwrap();
+        for file in [
+            "a.prod.conf",
+            "notes.txt",
+            "b.prod.conf.local",
+            "team/c.prod.yaml",
+            "team/nested/d.prod.conf",
+        ] {
+            std::fs::write(root.join(file), "key = value\n").unwrap();
+        }
+        // a link back to the root must not be walked forever
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&root, root.join("team/loop")).unwrap();
+        let (files, errors) = Publisher::enumerate_files(&root);
+        assert!(errors.is_empty());
+        let mut files: Vec<PathBuf> = files
             .into_iter()
-            .filter(
-                |(_config_path, _service, env)| match &self.config.filter_env {
-                    Some(filter_env) => env == filter_env,
-                    None => true,
-                },
-            )
+            .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
             .collect();
-        info!(
-            "Found {} filtered config paths",
-            &filtered_parsed_paths.len()
+        files.sort();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a.prod.conf"),
+                PathBuf::from("team/c.prod.yaml"),
+                PathBuf::from("team/nested/d.prod.conf"),
+            ]
         );
-        let per_config_stats = filtered_parsed_paths
-            .into_iter()
-            .map(|(config_path, service, env)| {
-                self.handle_config(config_path, service, env, dryrun)
+    }
+
+    #[test]
+    fn test_validate_files_offline() {
+        let config = Config {
+            config_path: "/nonexistent/myservice.myenv.conf".to_string(),
+            key_template: "config/{service}/{env}/{key}".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Publisher::validate_files(&config),
+            Err(Error::Invalid(1))
+        ));
+        let config = Config {
+            config_path: "/nonexistent/myservice.myenv.conf".to_string(),
+            key_template: "config/{service}/{key}/{env}".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Publisher::validate_files(&config),
+            Err(Error::Template(_))
+        ));
+    }
+
+    /// Serve Consul KV reads of `kvs` on a local port, accepting writes and recording request lines
+    fn mock_consul(kvs: &[(&str, &str, u64)]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        // Keys with base64 values, flags and modify indexes, updated by writes
+        let mut kvs: Vec<(String, String, u64, u64)> = kvs
+            .iter()
+            .map(|(key, value, flags)| {
+                (
+                    key.to_string(),
+                    general_purpose::STANDARD.encode(value),
+                    *flags,
+                    1,
+                )
             })
-            .collect::<Result<Vec<_>, Error>>()?;
-        let total_stats = per_config_stats
-            .into_iter()
-            .fold(PublishStats::default(), |acc, item| acc + item);
-        info!(
-            "For {} files found {} keys, updated {}, deleted {}",
-            configs_count, total_stats.count, total_stats.changed, total_stats.removed,
+            .collect();
+        let mut index = 1;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", method, target));
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let (status, body) = match (method, path.strip_prefix("/v1/kv/")) {
+                    ("GET", None) if path == "/v1/status/leader" => {
+                        (200, String::from("\"127.0.0.1:8300\""))
+                    }
+                    ("GET", Some(prefix)) if query.split('&').any(|param| param == "keys") => {
+                        let names: Vec<&String> = kvs
+                            .iter()
+                            .map(|(name, _, _, _)| name)
+                            .filter(|name| name.starts_with(prefix))
+                            .collect();
+                        match names.is_empty() {
+                            true => (404, String::new()),
+                            false => (200, serde_json::to_string(&names).unwrap()),
+                        }
+                    }
+                    ("GET", Some(key)) => {
+                        let pairs: Vec<serde_json::Value> = kvs
+                            .iter()
+                            .filter(|(name, _, _, _)| {
+                                name == key || (query.contains("recurse") && name.starts_with(key))
+                            })
+                            .map(|(name, value, flags, modify_index)| {
+                                serde_json::json!({
+                                    "Key": name, "Value": value, "Flags": flags,
+                                    "CreateIndex": 1, "ModifyIndex": modify_index, "LockIndex": 0
+                                })
+                            })
+                            .collect();
+                        match pairs.is_empty() {
+                            true => (404, String::new()),
+                            false => (200, serde_json::to_string(&pairs).unwrap()),
+                        }
+                    }
+                    ("PUT", Some(key)) => {
+                        let flags = query
+                            .split('&')
+                            .find_map(|param| param.strip_prefix("flags="))
+                            .map_or(0, |flags| flags.parse().unwrap());
+                        index += 1;
+                        kvs.retain(|(name, _, _, _)| name != key);
+                        kvs.push((
+                            key.to_string(),
+                            general_purpose::STANDARD.encode(&body),
+                            flags,
+                            index,
+                        ));
+                        (200, String::from("true"))
+                    }
+                    ("DELETE", Some(key)) => {
+                        kvs.retain(|(name, _, _, _)| {
+                            name != key && !(query.contains("recurse") && name.starts_with(key))
+                        });
+                        (200, String::from("true"))
+                    }
+                    ("PUT", None) if path == "/v1/txn" => {
+                        let ops: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                        for op in ops.as_array().unwrap() {
+                            let op = op.get("KV").unwrap();
+                            let field = |name: &str| op.get(name).and_then(|field| field.as_str());
+                            let key = field("Key").unwrap().to_string();
+                            kvs.retain(|(name, _, _, _)| *name != key);
+                            if field("Verb") != Some("delete") {
+                                index += 1;
+                                let flags = op.get("Flags").and_then(|flags| flags.as_u64());
+                                let value = field("Value").unwrap_or("").to_string();
+                                kvs.push((key, value, flags.unwrap_or(0), index));
+                            }
+                        }
+                        (200, String::from("{}"))
+                    }
+                    ("PUT", None) if path == "/v1/session/create" => {
+                        (200, String::from("{\"ID\": \"session-1\"}"))
+                    }
+                    ("PUT", None) if path.starts_with("/v1/session/destroy/") => {
+                        (200, String::from("true"))
+                    }
+                    _ => (404, String::new()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn test_init_header() {
+        let (consul_addr, _) = mock_consul(&[("config/app/prod/host", "db", 0)]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_init_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            key_template: String::from("config/{service}/{env}/{key}"),
+            service: Some(String::from("app")),
+            env: Some(String::from("prod")),
+            timeout: 5,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.init(&root, false);
+        let content = std::fs::read_to_string(root.join("app.prod.conf"));
+        let kv_config = KVConfig::new(&root.join("app.prod.conf"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(
+            content.unwrap(),
+            "# generated by consul_kv_config init with key template config/{service}/{env}/{key}\n\
+             host = db\n"
         );
+        // The header is a comment, not a key
+        assert_eq!(kv_config.unwrap().ordered_keys(), &["host"]);
+    }
 
-        Ok(())
+    #[test]
+    fn test_key_disposition_filtered() {
+        let (consul_addr, _) = mock_consul(&[
+            ("config/app/prod/host", "db", 0),
+            ("config/app/prod/secret_remote", "x", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_explain_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = db\nsecret_local = y\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            exclude_keys: vec![String::from("secret*")],
+            prune: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let source = Publisher::collect_config_paths(&publisher.config)
+            .unwrap()
+            .remove(0);
+        let changes = publisher.compute_changes(&source);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let changes = changes.unwrap();
+        let disposition = |key| publisher.key_disposition(&changes, &HashSet::new(), key);
+        assert_eq!(disposition("host"), "unchanged");
+        assert_eq!(disposition("secret_local"), "filtered by include/exclude");
+        assert_eq!(disposition("secret_remote"), "filtered by include/exclude");
+        assert!(changes.filtered_keys.contains("secret_local"));
     }
-}
 
-/// Tests
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_drift_report() {
+        let (consul_addr, _) = mock_consul(&[
+            ("config/app/prod/host", "old", 0),
+            ("config/app/prod/stale", "x", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_drift_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = new\nport = 80\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            output: OutputFormat::Json,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let source = Publisher::collect_config_paths(&publisher.config)
+            .unwrap()
+            .remove(0);
+        let changes = publisher.compute_changes(&source);
+        let verified = publisher.verify(true);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(verified.is_ok());
+        let report = publisher.drift_report(&changes.unwrap());
+        let state = |key: &str| report.get(key)?.get("state")?.as_str();
+        assert_eq!(state("host"), Some("changed"));
+        assert_eq!(state("port"), Some("missing"));
+        assert_eq!(state("stale"), Some("removed"));
+        let value = |key: &str, side: &str| report.get(key)?.get(side)?.as_str();
+        assert_eq!(value("host", "consul"), Some("old"));
+        assert_eq!(value("host", "config"), Some("new"));
+        assert_eq!(value("port", "consul"), None);
+    }
 
     #[test]
-    fn test_parse_filename() {
-        let mut res =
-            Publisher::deduce_service_env_from_filename(&"myservice.myenv.conf".to_owned());
-        assert_eq!(res.unwrap(), ("myservice".to_string(), "myenv".to_string()));
-        assert!(
-            Publisher::deduce_service_env_from_filename(&"myservice.myenv.txt".to_owned()).is_err()
+    fn test_atomic_verify_writes() {
+        let (consul_addr, requests) = mock_consul(&[("config/app/prod/host", "old", 0)]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_atomic_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = new\nport = 80\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            atomic: true,
+            verify_writes: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(result.unwrap(), 2);
+        // Written keys are read back after the transaction
+        let requests = requests.lock().unwrap();
+        let txn = requests
+            .iter()
+            .position(|request| request == "PUT /v1/txn")
+            .unwrap();
+        assert_eq!(
+            requests[txn + 1..],
+            [
+                "GET /v1/kv/config/app/prod/host",
+                "GET /v1/kv/config/app/prod/port"
+            ]
         );
-        assert!(Publisher::deduce_service_env_from_filename(&"myenv.conf".to_owned()).is_err());
-        assert!(Publisher::deduce_service_env_from_filename(&"..conf".to_owned()).is_err());
-        assert!(Publisher::deduce_service_env_from_filename(&"s.e.conf".to_owned()).is_ok());
-        res = Publisher::deduce_service_env_from_filename(&"my_service.my_env123.conf".to_owned());
+    }
+
+    #[test]
+    fn test_dryrun_read_only() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/host", "old", 0),
+            ("config/app/prod/stale", "x", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_dryrun_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = new\nport = 80\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            prune: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let dryrun_changes = publisher.process(true);
+        let dryrun_requests: Vec<String> = requests.lock().unwrap().drain(..).collect();
+        let published = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // A dryrun lists the prefix and computes all changes without a single write
+        assert_eq!(dryrun_changes.unwrap(), 3);
+        assert!(dryrun_requests
+            .iter()
+            .any(|request| request.starts_with("GET /v1/kv/config/app/prod/?recurse")));
+        assert!(dryrun_requests
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+        published.unwrap();
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .any(|request| request.starts_with("PUT /v1/kv/config/app/prod/port")));
+        assert!(requests
+            .iter()
+            .any(|request| request.starts_with("DELETE /v1/kv/config/app/prod/stale")));
+    }
+
+    #[test]
+    fn test_publish_lock() {
+        let (consul_addr, requests) = mock_consul(&[("config/app/prod/host", "old", 0)]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_lock_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = new\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            lock_template: Some(String::from("config/locks/{service}/{env}")),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        publisher.process(true).unwrap();
+        let dryrun_requests: Vec<String> = requests.lock().unwrap().drain(..).collect();
+        let published = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // A dryrun takes no lock
+        assert!(dryrun_requests
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+        // The lock is held around writes and released with its session afterwards
+        published.unwrap();
+        let writes: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| !request.starts_with("GET "))
+            .map(|request| request.split('?').next().unwrap().to_string())
+            .collect();
         assert_eq!(
-            res.unwrap(),
-            ("my_service".to_string(), "my_env123".to_string())
+            writes,
+            vec![
+                "PUT /v1/session/create",
+                "PUT /v1/kv/config/locks/app/prod",
+                "PUT /v1/kv/config/app/prod/host",
+                "PUT /v1/kv/config/locks/app/prod",
+                "PUT /v1/session/destroy/session-1",
+            ]
         );
-        assert!(Publisher::deduce_service_env_from_filename(
-            &"myservice.second.myenv.conf".to_owned()
+    }
+
+    #[test]
+    fn test_skip_unchanged() {
+        let (consul_addr, requests) = mock_consul(&[]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_cache_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = db\n").unwrap();
+        std::fs::write(root.join("web.prod.conf"), "port = 80\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            cache_file: Some(root.join("cache").to_str().unwrap().to_string()),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let first_run = publisher.process(false);
+        std::fs::write(root.join("web.prod.conf"), "port = 8080\n").unwrap();
+        requests.lock().unwrap().clear();
+        let second_run = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // A run without a cache publishes everything, the next one only the changed file
+        assert_eq!(first_run.unwrap(), 2);
+        assert_eq!(second_run.unwrap(), 1);
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .all(|request| !request.contains("/v1/kv/config/app/")));
+        assert!(requests
+            .iter()
+            .any(|request| request.starts_with("PUT /v1/kv/config/web/prod/port")));
+    }
+
+    #[test]
+    fn test_skip_unchanged_converges() {
+        let (consul_addr, requests) = mock_consul(&[]);
+        let root = std::env::temp_dir().join(format!(
+            "consul_kv_config_cache_converge_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = db\nport = 80\n").unwrap();
+        let config = Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            cache_file: Some(root.join("cache").to_str().unwrap().to_string()),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        };
+        // A file with deferred changes is not cached until all of them are made
+        let limited = Publisher::new(Config {
+            max_changes: Some(1),
+            ..config.clone()
+        })
+        .unwrap();
+        let deferred_runs = [limited.process(false), limited.process(false)];
+        // Each datacenter keeps its own fingerprint
+        let publisher = Publisher::new(Config {
+            datacenter: Some(String::from("dc1,dc2")),
+            ..config
+        })
+        .unwrap();
+        let first_run = publisher.process(false);
+        requests.lock().unwrap().clear();
+        let second_run = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let [first_deferred, second_deferred] = deferred_runs;
+        // Returned changes include deferred ones
+        assert_eq!(first_deferred.unwrap(), 2);
+        assert_eq!(second_deferred.unwrap(), 1);
+        assert!(first_run.is_ok());
+        assert_eq!(second_run.unwrap(), 0);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| !request.contains("/v1/kv/config/app/")));
+    }
+
+    #[test]
+    fn test_fingerprint_settings() {
+        let root = std::env::temp_dir().join(format!(
+            "consul_kv_config_fingerprint_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = ${DB_HOST}\nport = 80\n").unwrap();
+        let source = ConfigSource {
+            path: root.join("app.prod.conf"),
+            service: String::from("app"),
+            env: String::from("prod"),
+            matrix: false,
+            merged: None,
+            local_override: None,
+            base: None,
+        };
+        let fingerprint = |config: Config| {
+            Publisher::new(Config {
+                consul_addr: String::from("http://127.0.0.1:8500"),
+                config_path: root.to_str().unwrap().to_string(),
+                key_template: String::from("config/{service}/{env}/{key}"),
+                ..config
+            })
+            .unwrap()
+            .fingerprint(&source)
+            .unwrap()
+        };
+        let plain = fingerprint(Config::default());
+        // Settings changing what gets published change the fingerprint
+        let fingerprints = [
+            fingerprint(Config {
+                key_prefix_add: Some(String::from("v2/")),
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                exclude_keys: vec![String::from("port")],
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                rename_keys: HashMap::from([(String::from("port"), String::from("http_port"))]),
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                prune: true,
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                compress: true,
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                canonical_json: true,
+                ..Config::default()
+            }),
+            fingerprint(Config {
+                no_trim: true,
+                ..Config::default()
+            }),
+        ];
+        std::env::set_var("DB_HOST", "db1");
+        let expanded = fingerprint(Config {
+            expand_env: true,
+            ..Config::default()
+        });
+        std::env::set_var("DB_HOST", "db2");
+        let reexpanded = fingerprint(Config {
+            expand_env: true,
+            ..Config::default()
+        });
+        let replain = fingerprint(Config::default());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(plain, replain);
+        assert!(fingerprints.iter().all(|fingerprint| *fingerprint != plain));
+        assert_ne!(expanded, plain);
+        assert_ne!(expanded, reexpanded);
+    }
+
+    #[test]
+    fn test_audit_changes() {
+        let (consul_addr, _) = mock_consul(&[
+            ("config/app/prod/host", "old", 0),
+            ("config/app/prod/stale", "x", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_audit_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = new\nport = 80\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            prune: true,
+            audit: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let results = publisher.publish_files(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let changes = results.unwrap().changes;
+        let summary: Vec<(&str, &str, bool)> = changes
+            .iter()
+            .map(|event| (event.action, event.key.as_str(), event.existed))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("write", "host", true),
+                ("write", "port", false),
+                ("delete", "stale", true),
+            ]
+        );
+        // Written keys carry indexes of the writes, deleted keys have none
+        assert!(changes[0].modify_index.unwrap() > 1);
+        assert!(changes[1].create_index.is_some());
+        assert_eq!(changes[2].modify_index, None);
+    }
+
+    #[test]
+    fn test_publish_merged() {
+        let (consul_addr, requests) = mock_consul(&[]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_merged_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let merged_path = root.join("all.conf");
+        std::fs::write(
+            &merged_path,
+            "auth.prod.db_host = db\nweb.prod.port = 80\nauth.dev.db_host = localhost\n",
         )
-        .is_err());
-        res = Publisher::deduce_service_env_from_filename(&"my-service.my-env123.conf".to_owned());
+        .unwrap();
+        let config = Config {
+            consul_addr,
+            merged: Some(merged_path.to_str().unwrap().to_string()),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        };
+        let published = Publisher::new(config.clone()).unwrap().process(false);
+        std::fs::write(&merged_path, "auth.prod.db_host = db\ndb_port = 5432\n").unwrap();
+        let invalid = Publisher::new(config).unwrap().process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Each group of keys is published under its own service and env
+        assert_eq!(published.unwrap(), 3);
+        let requests = requests.lock().unwrap();
+        for key in ["auth/prod/db_host", "web/prod/port", "auth/dev/db_host"] {
+            assert!(requests
+                .iter()
+                .any(|request| request.starts_with(&format!("PUT /v1/kv/config/{}", key))));
+        }
+        assert!(invalid
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("key db_port does not match merged key pattern"));
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/host", "db", 0),
+            ("config/app/prod/db/port", "5432", 0),
+            ("config/app/dev/host", "localhost", 0),
+        ]);
+        let config = Config {
+            consul_addr,
+            key_template: String::from("config/{service}/{env}/{key}"),
+            service: Some(String::from("app")),
+            env: Some(String::from("prod")),
+            timeout: 5,
+            ..Config::default()
+        };
+        let publisher = Publisher::new(config.clone()).unwrap();
+        let dryrun_stats = publisher.delete_prefix(true, false).unwrap();
+        assert_eq!((dryrun_stats.existing, dryrun_stats.removed), (2, 2));
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+
+        let stats = publisher.delete_prefix(false, false).unwrap();
+        assert_eq!(stats.removed, 2);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.starts_with("DELETE /v1/kv/config/app/prod/")));
+
+        // A prefix rendering to the root is never deleted
+        let publisher = Publisher::new(Config {
+            key_template: String::from("{service}/{key}"),
+            service: Some(String::new()),
+            allow_empty_segments: true,
+            ..config
+        })
+        .unwrap();
+        assert!(matches!(
+            publisher.delete_prefix(false, false),
+            Err(Error::Template(_))
+        ));
+    }
+
+    #[test]
+    fn test_consul_timeout() {
+        // Consul accepting connections but never answering
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let consul_addr = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+            drop(streams);
+        });
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            key_template: String::from("config/{service}/{env}/{key}"),
+            consul_timeout: Some(1),
+            retries: 0,
+            ..Config::default()
+        })
+        .unwrap();
+        let service_config = ServiceConfig::new(
+            String::from("config/{service}/{env}/{key}"),
+            String::from("app"),
+            String::from("prod"),
+        );
+        let started = Instant::now();
+        let result = publisher.read_kv_from_consul(&service_config);
+        assert!(matches!(result, Err(Error::Unreachable)));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_repeated_run_no_writes() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/host", "db", 0),
+            ("config/app/prod/port", "5432", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_noop_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "host = db\nport = 5432\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            prune: true,
+            write_manifest: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let first_run = publisher.process(false);
+        let first_requests: Vec<String> = requests.lock().unwrap().drain(..).collect();
+        let second_run = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Only the missing manifest is written, then a repeated run only reads
+        assert_eq!(first_run.unwrap(), 0);
+        let first_writes: Vec<&String> = first_requests
+            .iter()
+            .filter(|request| !request.starts_with("GET "))
+            .collect();
+        assert_eq!(first_writes.len(), 1);
+        assert!(first_writes[0].starts_with("PUT /v1/kv/config/app/prod/_managed"));
+        assert_eq!(second_run.unwrap(), 0);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+    }
+
+    #[test]
+    fn test_rename_key() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/dbhost", "db", 0),
+            ("config/app/prod/port", "5432", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_rename_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "dbhost = db\nport = 5432\n").unwrap();
+        let config = Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            rename_keys: HashMap::from([(String::from("dbhost"), String::from("db_host"))]),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        };
+        let publisher = Publisher::new(config.clone()).unwrap();
+        let first_run = publisher.process(false);
+        let first_writes: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|request| !request.starts_with("GET "))
+            .map(|request| request.split('?').next().unwrap().to_string())
+            .collect();
+        // Both names in one file are ambiguous
+        std::fs::write(root.join("app.prod.conf"), "dbhost = db\ndb_host = db\n").unwrap();
+        let conflict = Publisher::validate_files(&config);
+        // The old key is gone without prune and the new one holds the same value
+        std::fs::write(root.join("app.prod.conf"), "db_host = db\nport = 5432\n").unwrap();
+        let second_run = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(first_run.unwrap(), 2);
         assert_eq!(
-            res.unwrap(),
-            ("my-service".to_string(), "my-env123".to_string())
+            first_writes,
+            vec![
+                String::from("PUT /v1/kv/config/app/prod/db_host"),
+                String::from("DELETE /v1/kv/config/app/prod/dbhost"),
+            ]
+        );
+        assert!(matches!(conflict, Err(Error::Invalid(1))));
+        assert_eq!(second_run.unwrap(), 0);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+    }
+
+    #[test]
+    fn test_rename_key_max_changes() {
+        let (consul_addr, requests) = mock_consul(&[("config/app/prod/dbhost", "db", 0)]);
+        let root = std::env::temp_dir().join(format!(
+            "consul_kv_config_rename_limit_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "dbhost = db\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            rename_keys: HashMap::from([(String::from("dbhost"), String::from("db_host"))]),
+            max_changes: Some(1),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let mut runs = Vec::new();
+        for _ in 0..3 {
+            let result = publisher.process(false);
+            let writes: Vec<String> = requests
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter(|request| !request.starts_with("GET "))
+                .map(|request| request.split('?').next().unwrap().to_string())
+                .collect();
+            runs.push((result, writes));
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // The deletion of the old key is deferred to the next run by the limit
+        let mut runs = runs.into_iter();
+        let (result, writes) = runs.next().unwrap();
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(writes, ["PUT /v1/kv/config/app/prod/db_host"]);
+        let (result, writes) = runs.next().unwrap();
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(writes, ["DELETE /v1/kv/config/app/prod/dbhost"]);
+        let (result, writes) = runs.next().unwrap();
+        assert_eq!(result.unwrap(), 0);
+        assert!(writes.is_empty());
+    }
+
+    #[test]
+    fn test_backup_in_dryrun() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/host", "old-db", 0),
+            ("config/app/prod/color", "#fff", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_backup_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("configs")).unwrap();
+        std::fs::write(root.join("configs/app.prod.conf"), "host = db\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.join("configs").to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            backup_dir: Some(root.join("backups").to_str().unwrap().to_string()),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.process(true);
+        let backup_path = root
+            .join("backups")
+            .join(publisher.started.to_string())
+            .join("app.prod.conf");
+        let backup = std::fs::read_to_string(&backup_path);
+        let restored = KVConfig::new(&backup_path);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(result.unwrap(), 1);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+        assert!(backup.unwrap().ends_with("color = \\#fff\nhost = old-db\n"));
+        let restored = restored.unwrap();
+        assert_eq!(restored.get("host").unwrap(), "old-db");
+        assert_eq!(restored.get("color").unwrap(), "#fff");
+    }
+
+    #[test]
+    fn test_read_keys_first() {
+        let other_keys: Vec<(String, String)> = (0..5000)
+            .map(|n| (format!("config/app/prod/other/{}", n), n.to_string()))
+            .collect();
+        let mut kvs: Vec<(&str, &str, u64)> = other_keys
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str(), 0))
+            .collect();
+        kvs.extend([
+            ("config/app/prod/host", "old-db", 0),
+            ("config/app/prod/port", "5432", 0),
+            ("config/app/prod/stale", "1", 0),
+        ]);
+        let (consul_addr, requests) = mock_consul(&kvs);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_keys_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("app.prod.conf"),
+            "host = db\nport = 5432\nuser = app\n",
+        )
+        .unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            exclude_keys: vec![String::from("other/*")],
+            read_keys_first: true,
+            verify_writes: true,
+            audit: true,
+            prune: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // One listing of names, then values of local keys only, the new key is never read before writing
+        assert_eq!(result.unwrap(), 3);
+        let requests: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| !request.contains("/status/leader"))
+            .map(|request| request.split('?').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            requests[..3],
+            [
+                "GET /v1/kv/config/app/prod/",
+                "GET /v1/kv/config/app/prod/host",
+                "GET /v1/kv/config/app/prod/port",
+            ]
+        );
+        // Written keys are read back by verification and audit, in any order of writes
+        let mut writes = requests[3..].to_vec();
+        writes.sort();
+        assert_eq!(
+            writes,
+            [
+                "DELETE /v1/kv/config/app/prod/stale",
+                "GET /v1/kv/config/app/prod/host",
+                "GET /v1/kv/config/app/prod/host",
+                "GET /v1/kv/config/app/prod/user",
+                "GET /v1/kv/config/app/prod/user",
+                "PUT /v1/kv/config/app/prod/host",
+                "PUT /v1/kv/config/app/prod/user",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_settings_directives() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("kv/app/prod/host", "old-db", 0),
+            ("kv/app/prod/stale", "1", 0),
+        ]);
+        let root = std::env::temp_dir().join(format!(
+            "consul_kv_config_directives_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        // The file name does not tell the service and env
+        std::fs::write(
+            root.join("notes.conf"),
+            "_service = app\n_env = prod\n_template = kv/{service}/{env}/{key}\n\
+             _prune = true\nhost = db\n",
+        )
+        .unwrap();
+        let config = Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        };
+        let from_file = Publisher::new(config.clone()).unwrap().process(false);
+        let file_writes: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|request| !request.starts_with("GET "))
+            .map(|request| request.split('?').next().unwrap().to_string())
+            .collect();
+        // A key template given on the command line wins
+        let from_cli = Publisher::new(Config {
+            key_template_fixed: true,
+            ..config
+        })
+        .unwrap()
+        .process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(from_file.unwrap(), 2);
+        assert_eq!(
+            file_writes,
+            vec![
+                String::from("PUT /v1/kv/kv/app/prod/host"),
+                String::from("DELETE /v1/kv/kv/app/prod/stale"),
+            ]
+        );
+        assert_eq!(from_cli.unwrap(), 1);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.starts_with("PUT /v1/kv/config/app/prod/host")));
+    }
+
+    #[test]
+    fn test_publish_single_value_missing() {
+        // The mock answers 404 to reads of the missing key as Consul does
+        let (consul_addr, requests) = mock_consul(&[]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_single_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.prod.conf"), "value = 42\n").unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}"),
+            single_value: true,
+            verify_writes: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(result.unwrap(), 1);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.starts_with("PUT /v1/kv/config/app/prod")));
+    }
+
+    #[test]
+    fn test_publish_compressed_reference() {
+        let stored = Publisher::compress_value("postgres://db").unwrap();
+        let (consul_addr, _) =
+            mock_consul(&[("config/shared/prod/db_url", &stored, COMPRESSED_FLAGS)]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_reference_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("app.prod.conf"),
+            "db_url @ref= config/shared/prod/db_url\n",
+        )
+        .unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let result = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(result.unwrap(), 1);
+        let pair = publisher
+            .read_pair("config/app/prod/db_url", "")
+            .unwrap()
+            .unwrap();
+        assert_eq!(pair.stored, "postgres://db");
+        // A missing referenced key is an error, not a parse failure
+        let service_config = ServiceConfig::new(
+            String::from("config/{service}/{env}/{key}"),
+            String::from("app"),
+            String::from("prod"),
         );
+        assert!(matches!(
+            publisher.read_reference(&service_config, "config/shared/prod/missing"),
+            Err(Error::Reference(_))
+        ));
+    }
+
+    #[test]
+    fn test_nested_keys_not_removed() {
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/db/", "", 0),
+            ("config/app/prod/db/host", "db", 0),
+            ("config/app/prod/cache/redis/host", "redis", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_nested_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("app.prod.conf"),
+            "[db]\nhost = db\n[cache/redis]\nhost = redis\n",
+        )
+        .unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            prune: true,
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let changes = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(changes.unwrap(), 0);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("GET ")));
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let value = "x".repeat(200);
+        let stored = Publisher::compress_value(&value).unwrap();
+        let (consul_addr, requests) = mock_consul(&[
+            ("config/app/prod/large", &stored, COMPRESSED_FLAGS),
+            ("config/app/prod/small", "y", 0),
+        ]);
+        let root =
+            std::env::temp_dir().join(format!("consul_kv_config_gzip_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("app.prod.conf"),
+            format!("large = {}\nsmall = y\n", value),
+        )
+        .unwrap();
+        let publisher = Publisher::new(Config {
+            consul_addr,
+            config_path: root.to_str().unwrap().to_string(),
+            key_template: String::from("config/{service}/{env}/{key}"),
+            compress: true,
+            compress_threshold: Some(100),
+            timeout: 5,
+            jobs: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let unchanged = publisher.process(true);
+        std::fs::write(
+            root.join("app.prod.conf"),
+            format!("large = {}z\nsmall = y\n", value),
+        )
+        .unwrap();
+        let changed = publisher.process(false);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Values are compared decompressed
+        assert_eq!(unchanged.unwrap(), 0);
+        assert_eq!(changed.unwrap(), 1);
+        let requests = requests.lock().unwrap();
+        let put = requests
+            .iter()
+            .find(|request| request.starts_with("PUT "))
+            .unwrap();
+        assert!(put.starts_with("PUT /v1/kv/config/app/prod/large"));
+        assert!(put.contains(&format!("flags={}", COMPRESSED_FLAGS)));
     }
 }